@@ -12,15 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::{ensure, Context as _};
+use anyhow::{ensure, Context as _, Result};
 use chrono::Local;
 use serde::Serialize;
 use std::fs::File;
-use std::io::{BufWriter, Read as _, Write as _};
+use std::io::{BufWriter, Write as _};
 use std::path::{Component, Path, PathBuf};
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
-use webbundle::{Bundle, Result, Uri, Version};
+use webbundle::{Bundle, EncoderOptions, Uri, Version};
 
 #[derive(StructOpt)]
 struct Cli {
@@ -47,6 +47,9 @@ enum Command {
         primary_url: String,
         #[structopt(short = "m", long = "manifest")]
         manifest: Option<String>,
+        /// Write identical responses only once
+        #[structopt(long = "dedup")]
+        dedup: bool,
         /// File name
         file: String,
         /// Directory from where resources are read
@@ -93,7 +96,9 @@ fn list(bundle: &Bundle, format: Option<Format>) {
 }
 
 fn list_plain(bundle: &Bundle) {
-    println!("primary-url: {}", bundle.primary_url());
+    if let Some(primary_url) = bundle.primary_url() {
+        println!("primary-url: {}", primary_url);
+    }
     if let Some(manifest) = bundle.manifest() {
         println!("manifest: {}", manifest);
     }
@@ -136,14 +141,14 @@ fn list_json(bundle: &Bundle) {
     #[derive(Serialize)]
     struct Bundle<'a> {
         version: &'a [u8],
-        primary_url: String,
+        primary_url: &'a Option<String>,
         manifest: &'a Option<String>,
         exchanges: Vec<Exchange>,
     }
 
     let bundle = Bundle {
         version: bundle.version().bytes(),
-        primary_url: bundle.primary_url().to_string(),
+        primary_url: &bundle.primary_url().as_ref().map(|uri| uri.to_string()),
         manifest: &bundle.manifest().as_ref().map(|uri| uri.to_string()),
         exchanges: bundle
             .exchanges()
@@ -279,6 +284,7 @@ async fn main() -> Result<()> {
             primary_url,
             file,
             manifest,
+            dedup,
             resources_dir,
         } => {
             let mut builder = Bundle::builder()
@@ -292,24 +298,19 @@ async fn main() -> Result<()> {
             let bundle = builder.build()?;
             log::debug!("{:#?}", bundle);
             let write = BufWriter::new(File::create(&file)?);
-            bundle.write_to(write)?;
+            bundle
+                .write_to_with_options(write, &EncoderOptions::default().dedup_responses(dedup))?;
         }
         Command::List { file, format } => {
-            let mut buf = Vec::new();
-            File::open(&file)?.read_to_end(&mut buf)?;
-            let bundle = Bundle::from_bytes(buf)?;
+            let bundle = Bundle::open(&file)?;
             list(&bundle, format);
         }
         Command::Dump { file } => {
-            let mut buf = Vec::new();
-            File::open(&file)?.read_to_end(&mut buf)?;
-            let bundle = Bundle::from_bytes(buf)?;
+            let bundle = Bundle::open(&file)?;
             println!("{:#?}", bundle);
         }
         Command::Extract { file } => {
-            let mut buf = Vec::new();
-            File::open(&file)?.read_to_end(&mut buf)?;
-            let bundle = Bundle::from_bytes(buf)?;
+            let bundle = Bundle::open(&file)?;
             extract(&bundle)?;
         }
     }
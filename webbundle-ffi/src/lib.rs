@@ -3,7 +3,7 @@ use libc::size_t;
 use std::os::raw::{c_char, c_int};
 use std::ptr;
 use std::slice;
-use webbundle::Bundle;
+use webbundle::{Bundle, Bytes};
 
 pub struct WebBundle(Bundle);
 
@@ -20,8 +20,8 @@ pub struct WebBundle(Bundle);
 /// [`webbundle_destroy()`]: fn.webbundle_destroy.html
 #[no_mangle]
 pub unsafe extern "C" fn webbundle_parse(bytes: *const c_char, length: size_t) -> *const WebBundle {
-    let slice = slice::from_raw_parts(bytes as *mut u8, length as usize);
-    match Bundle::from_bytes(slice) {
+    let slice = slice::from_raw_parts(bytes as *mut u8, length);
+    match Bundle::from_bytes(Bytes::copy_from_slice(slice)) {
         Ok(bundle) => Box::into_raw(Box::new(WebBundle(bundle))),
         Err(_) => ptr::null(),
     }
@@ -45,6 +45,7 @@ pub unsafe extern "C" fn webbundle_destroy(bundle: *mut WebBundle) {
 /// returning the number of bytes copied.
 ///
 /// If user-provided buffer's length is not enough, this returns `-1`.
+/// If the bundle doesn't have a primary_url, this returns `0`.
 ///
 /// # Safety
 ///
@@ -60,9 +61,12 @@ pub unsafe extern "C" fn webbundle_primary_url(
         return -1;
     }
     let bundle: &Bundle = &((*bundle).0);
-    let uri = bundle.primary_url().to_string();
+    let uri = match bundle.primary_url() {
+        Some(uri) => uri.to_string(),
+        None => return 0,
+    };
 
-    let buffer: &mut [u8] = slice::from_raw_parts_mut(buffer as *mut u8, length as usize);
+    let buffer: &mut [u8] = slice::from_raw_parts_mut(buffer as *mut u8, length);
 
     if buffer.len() < uri.len() {
         return -1;
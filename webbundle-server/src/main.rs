@@ -3,7 +3,6 @@ use chrono::Local;
 use headers::{ContentLength, ContentType, HeaderMapExt as _};
 use http::{Response, StatusCode};
 use hyper::Body;
-use serde::Deserialize;
 use std::io::Write as _;
 use std::path::{Component, Path, PathBuf};
 use structopt::StructOpt;
@@ -30,8 +29,18 @@ struct Cli {
 
 type AndThenResult<T> = std::result::Result<T, warp::reject::Rejection>;
 
-fn env_logger_init() {
-    env_logger::builder()
+fn env_logger_init(verbose: u64) {
+    let mut builder = env_logger::builder();
+    match verbose {
+        0 => {}
+        1 => {
+            builder.filter_level(log::LevelFilter::Debug);
+        }
+        _ => {
+            builder.filter_level(log::LevelFilter::Trace);
+        }
+    }
+    builder
         .format(|buf, record| {
             writeln!(
                 buf,
@@ -49,8 +58,8 @@ fn env_logger_init() {
 
 #[tokio::main]
 async fn main() {
-    env_logger_init();
     let args = Cli::from_args();
+    env_logger_init(args.verbose);
 
     let addr = (
         if args.bind_all {
@@ -126,11 +135,6 @@ async fn webbundle_reply(base_dir: impl AsRef<Path>) -> Result<Response<Body>> {
     ))
 }
 
-#[derive(Debug, Deserialize, Default)]
-struct WebBundleQuery {
-    render: Option<String>,
-}
-
 async fn static_file_reply(path: impl AsRef<Path>) -> Result<Response<Body>> {
     let path = path.as_ref();
     log::debug!("static_file_serv: path: {}", path.display());
@@ -0,0 +1,26 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webbundle::{Bundle, Limits};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits::default().max_body_size(1 << 20);
+    if let Ok(bundle) = Bundle::from_bytes_with_limits(data.to_vec(), &limits) {
+        // A parsed bundle must be encodable, or fail gracefully.
+        let _ = bundle.encode();
+    }
+});
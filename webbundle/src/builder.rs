@@ -12,10 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::bundle::{Bundle, Exchange, Request, Response, Uri, Version};
+use crate::bundle::{Body, Bundle, Exchange, Request, Response, Uri, Version};
+use crate::manifest::{WebAppManifest, MANIFEST_FILE_NAMES};
+use crate::options::HeaderPolicy;
 use crate::prelude::*;
+use crate::signatures::Signer;
+use crate::validation;
+use crate::variants;
 use headers::{ContentLength, ContentType, HeaderMapExt as _, HeaderValue};
-use http::StatusCode;
+use http::{Method, StatusCode};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::prelude::*;
@@ -28,7 +34,14 @@ pub struct Builder {
     version: Option<Version>,
     primary_url: Option<Uri>,
     manifest: Option<Uri>,
+    /// The manifest which `exchanges_from_dir` finds, used in version b1
+    /// unless the manifest url is set.
+    detected_manifest: Option<Uri>,
     exchanges: Vec<Exchange>,
+    signer: Option<Signer>,
+    critical: Vec<String>,
+    custom_sections: Vec<(String, Vec<u8>)>,
+    header_policy: HeaderPolicy,
 }
 
 impl Builder {
@@ -60,6 +73,41 @@ impl Builder {
         self
     }
 
+    /// Signs the exchanges with the given signer.
+    ///
+    /// A `digest` header is added to each response which doesn't have one.
+    /// Signatures are supported only in version b1.
+    pub fn sign(mut self, signer: Signer) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Marks the section of the given name as critical, so that a parser which
+    /// doesn't understand the section fails to parse the bundle.
+    ///
+    /// [`build`](Builder::build) fails if the bundle doesn't have the section.
+    pub fn critical_section(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        if !self.critical.contains(&name) {
+            self.critical.push(name);
+        }
+        self
+    }
+
+    /// Appends a section which this library doesn't understand. `bytes` must
+    /// be an encoded CBOR item, which is written as is.
+    pub fn custom_section(mut self, name: impl Into<String>, bytes: impl Into<Vec<u8>>) -> Self {
+        self.custom_sections.push((name.into(), bytes.into()));
+        self
+    }
+
+    /// Sets the policy for response headers which a bundle must not have.
+    /// [`build`](Builder::build) fails on them by default.
+    pub fn header_policy(mut self, header_policy: HeaderPolicy) -> Self {
+        self.header_policy = header_policy;
+        self
+    }
+
     /// Append exchanges from files rooted at the given directory.
     ///
     /// `base_url` will be used as a prefix for each resource. A relative path
@@ -72,6 +120,10 @@ impl Builder {
     /// 2. The URL for `index.html` file is a redirect to the parent directory
     ///    (`301` MOVED PERMANENTLY).
     ///
+    /// A `manifest.webmanifest` or `manifest.json` file which is a web app
+    /// manifest becomes the manifest of a b1 bundle, unless the manifest url
+    /// is set. The one closest to the given directory is chosen.
+    ///
     /// # Examples
     ///
     /// ```no_run
@@ -82,7 +134,7 @@ impl Builder {
     ///     .primary_url("https://example.com/".parse()?)
     ///     .exchanges_from_dir("build", "https://example.com".parse()?).await?
     ///     .build()?;
-    /// # std::result::Result::Ok::<_, anyhow::Error>(bundle)
+    /// # std::result::Result::Ok::<_, webbundle::Error>(bundle)
     /// # };
     /// ```
     pub async fn exchanges_from_dir(
@@ -90,24 +142,158 @@ impl Builder {
         dir: impl AsRef<Path>,
         base_url: Url,
     ) -> Result<Self> {
-        self.exchanges.append(
-            &mut ExchangeBuilder::new(PathBuf::from(dir.as_ref()), base_url)
-                .walk()
-                .await?
-                .build(),
-        );
+        let exchange_builder = ExchangeBuilder::new(PathBuf::from(dir.as_ref()), base_url)
+            .walk()
+            .await?;
+        if let Some((_, manifest)) = &exchange_builder.manifest {
+            self.detected_manifest = Some(manifest.clone());
+        }
+        self.exchanges.append(&mut exchange_builder.build());
         Ok(self)
     }
 
     /// Builds the bundle.
-    pub fn build(self) -> Result<Bundle> {
-        Ok(Bundle {
-            version: self.version.context("no version")?,
-            primary_url: self.primary_url.context("no primary_url")?,
-            manifest: self.manifest,
+    ///
+    /// Returns an error if the bundle has fields which the given version
+    /// cannot represent, such as a manifest in version b2, or if exchanges for
+    /// the same URL are not its variants.
+    pub fn build(mut self) -> Result<Bundle> {
+        let version = self
+            .version
+            .ok_or_else(|| Error::InvalidBundle("no version".to_string()))?;
+        match version {
+            Version::VersionB1 => {
+                ensure!(
+                    self.primary_url.is_some(),
+                    Error::InvalidBundle("no primary_url".to_string())
+                );
+                // Exchanges for the same URL must be its variants.
+                let mut exchanges_by_url = HashMap::<String, Vec<&Exchange>>::new();
+                for exchange in &self.exchanges {
+                    let others = exchanges_by_url
+                        .entry(crate::bundle::url_key(exchange.request.uri()))
+                        .or_default();
+                    crate::bundle::check_same_url(&version, exchange, others.iter().copied())?;
+                    others.push(exchange);
+                }
+            }
+            Version::VersionB2 | Version::Version1 => {
+                ensure!(
+                    self.manifest.is_none(),
+                    Error::Unsupported(format!(
+                        "manifest is not supported in version {:?}",
+                        version
+                    ))
+                );
+                ensure!(
+                    self.signer.is_none(),
+                    Error::Unsupported(format!(
+                        "signatures are not supported in version {:?}",
+                        version
+                    ))
+                );
+                let mut uris = HashSet::new();
+                for exchange in &self.exchanges {
+                    ensure!(
+                        uris.insert(crate::bundle::url_key(exchange.request.uri())),
+                        Error::Unsupported(format!(
+                            "variants are not supported in version {:?}: {}",
+                            version,
+                            exchange.request.uri()
+                        ))
+                    );
+                }
+            }
+            Version::Unknown(_) => return Err(Error::UnsupportedVersion(version)),
+        }
+        for exchange in &mut self.exchanges {
+            check_request(&version, exchange)?;
+            validation::apply_header_policy(self.header_policy, exchange)?;
+        }
+        let known_section_names: &[&str] = match version {
+            Version::VersionB1 => &crate::bundle::KNOWN_SECTION_NAMES_B1,
+            _ => &crate::bundle::KNOWN_SECTION_NAMES,
+        };
+        let mut names = HashSet::new();
+        for (name, bytes) in &self.custom_sections {
+            ensure!(
+                !known_section_names.contains(&name.as_str()) && names.insert(name),
+                Error::InvalidBundle(format!("Invalid custom section name: {}", name))
+            );
+            crate::decoder::check_cbor_item(bytes)?;
+        }
+        let signatures = match &self.signer {
+            Some(signer) => {
+                Signer::add_digest_headers(&mut self.exchanges)?;
+                Some(signer.sign(&self.exchanges)?)
+            }
+            None => None,
+        };
+        let manifest = match version {
+            Version::VersionB1 => self.manifest.or(self.detected_manifest),
+            _ => self.manifest,
+        };
+        let bundle = Bundle {
+            version,
+            primary_url: self.primary_url,
+            manifest,
+            signatures,
+            critical: self.critical,
+            custom_sections: self.custom_sections,
+            section_order: Vec::new(),
+            integrity_block: None,
             exchanges: self.exchanges,
-        })
+            url_index: Default::default(),
+            shares_responses: false,
+        };
+        let section_names = bundle.section_names();
+        for name in &bundle.critical {
+            ensure!(
+                section_names.contains(&name.as_str()),
+                Error::InvalidBundle(format!("No section for the critical section: {}", name))
+            );
+        }
+        Ok(bundle)
+    }
+}
+
+/// Checks that the request of the given exchange can be represented in the
+/// given version.
+///
+/// A request is a GET request. Only in version b1 can it have headers, which
+/// must be axes of the `Variants` header of the response, such as
+/// `Accept-Language`.
+pub(crate) fn check_request(version: &Version, exchange: &Exchange) -> Result<()> {
+    let request = &exchange.request;
+    ensure!(
+        request.method() == Method::GET,
+        Error::Unsupported(format!(
+            "{} requests are not supported: {}",
+            request.method(),
+            request.uri()
+        ))
+    );
+    if request.headers().is_empty() {
+        return Ok(());
     }
+    let variants = match version {
+        Version::VersionB1 => variants::variants_of(exchange.response.headers())?,
+        _ => None,
+    };
+    for name in request.headers().keys() {
+        ensure!(
+            variants
+                .as_ref()
+                .is_some_and(|variants| variants.has_axis(name)),
+            Error::Unsupported(format!(
+                "request header {} is not supported in version {:?}: {}",
+                name,
+                version,
+                request.uri()
+            ))
+        );
+    }
+    Ok(())
 }
 
 #[allow(dead_code)]
@@ -115,6 +301,9 @@ struct ExchangeBuilder {
     base_url: Url,
     base_dir: PathBuf,
     exchanges: Vec<Exchange>,
+    /// The URL of the manifest found so far, with its depth and the
+    /// preference of its file name.
+    manifest: Option<((usize, usize), Uri)>,
 }
 
 #[allow(dead_code)]
@@ -124,13 +313,14 @@ impl ExchangeBuilder {
             base_dir,
             base_url,
             exchanges: Vec::new(),
+            manifest: None,
         }
     }
 
     async fn walk(mut self) -> Result<Self> {
         // TODO: Walkdir is not async.
         for entry in WalkDir::new(&self.base_dir) {
-            let entry = entry?;
+            let entry = entry.map_err(std::io::Error::from)?;
             log::info!("visit: {:?}", entry);
             let file_type = entry.file_type();
             if file_type.is_symlink() {
@@ -156,11 +346,38 @@ impl ExchangeBuilder {
             } else {
                 let relative_path = pathdiff::diff_paths(entry.path(), &self.base_dir).unwrap();
                 self = self.exchange(&relative_path, &relative_path).await?;
+                if let Some(preference) = MANIFEST_FILE_NAMES
+                    .iter()
+                    .position(|name| entry.file_name() == *name)
+                {
+                    self.detect_manifest((entry.depth(), preference));
+                }
             }
         }
         Ok(self)
     }
 
+    /// Records the last exchange as the manifest if it is a web app manifest
+    /// which is preferred to the one found so far.
+    fn detect_manifest(&mut self, key: (usize, usize)) {
+        let exchange = self.exchanges.last().unwrap();
+        let uri = exchange.request.uri();
+        match WebAppManifest::parse(exchange.response.body(), uri) {
+            Ok(manifest) if manifest.start_url.is_some() || manifest.name.is_some() => {}
+            Ok(_) => {
+                log::info!("{} is not a web app manifest: No start_url or name", uri);
+                return;
+            }
+            Err(err) => {
+                log::info!("{} is not a web app manifest: {}", uri, err);
+                return;
+            }
+        }
+        if self.manifest.as_ref().is_none_or(|(other, _)| key < *other) {
+            self.manifest = Some((key, uri.clone()));
+        }
+    }
+
     fn build(self) -> Vec<Exchange> {
         self.exchanges
     }
@@ -168,7 +385,7 @@ impl ExchangeBuilder {
     fn url_from_relative_path(&self, relative_path: &Path) -> Result<Uri> {
         ensure!(
             relative_path.is_relative(),
-            format!("Path is not relative: {}", relative_path.display())
+            Error::InvalidUrl(format!("Path is not relative: {}", relative_path.display()))
         );
         Ok(self
             .base_url
@@ -202,7 +419,7 @@ impl ExchangeBuilder {
     }
 
     fn create_redirect(location: &str) -> Result<Response> {
-        let mut response = Response::new(Vec::new());
+        let mut response = Response::new(Body::new());
         *response.status_mut() = StatusCode::MOVED_PERMANENTLY;
         response
             .headers_mut()
@@ -213,7 +430,10 @@ impl ExchangeBuilder {
     async fn create_response(&self, relative_path: impl AsRef<Path>) -> Result<Response> {
         ensure!(
             relative_path.as_ref().is_relative(),
-            format!("Path is not relative: {}", relative_path.as_ref().display())
+            Error::InvalidUrl(format!(
+                "Path is not relative: {}",
+                relative_path.as_ref().display()
+            ))
         );
         let path = self.base_dir.join(relative_path);
 
@@ -224,7 +444,7 @@ impl ExchangeBuilder {
         let content_length = ContentLength(body.len() as u64);
         let content_type = ContentType::from(mime_guess::from_path(&path).first_or_octet_stream());
 
-        let mut response = Response::new(body);
+        let mut response = Response::new(body.into());
         *response.status_mut() = StatusCode::OK;
         response.headers_mut().typed_insert(content_length);
         response.headers_mut().typed_insert(content_type);
@@ -253,10 +473,123 @@ mod tests {
             .primary_url("https://example.com".parse()?)
             .build()?;
         assert_eq!(bundle.version, Version::Version1);
-        assert_eq!(bundle.primary_url, "https://example.com".parse::<Uri>()?);
+        assert_eq!(
+            bundle.primary_url,
+            Some("https://example.com".parse::<Uri>()?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_primary_url() -> Result<()> {
+        assert!(Builder::new().version(Version::VersionB1).build().is_err());
+        let bundle = Builder::new().version(Version::Version1).build()?;
+        assert_eq!(bundle.primary_url, None);
+        let bundle = Builder::new().version(Version::VersionB2).build()?;
+        assert_eq!(bundle.primary_url, None);
         Ok(())
     }
 
+    #[test]
+    fn build_manifest() -> Result<()> {
+        let manifest: Uri = "https://example.com/manifest.json".parse()?;
+        assert!(Builder::new()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com".parse()?)
+            .manifest(manifest.clone())
+            .build()
+            .is_ok());
+        assert!(Builder::new()
+            .version(Version::VersionB2)
+            .manifest(manifest.clone())
+            .build()
+            .is_err());
+        assert!(Builder::new()
+            .version(Version::Version1)
+            .manifest(manifest)
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_variants() -> Result<()> {
+        let exchange = || -> Result<Exchange> {
+            Ok(Exchange {
+                request: Request::get("https://example.com/").body(())?,
+                response: Response::new(Body::new()),
+            })
+        };
+        let variant =
+            |key| crate::test_util::variant("https://example.com/", "Accept-Language;en;ja", key);
+        let build = |exchanges: Vec<Exchange>| {
+            exchanges
+                .into_iter()
+                .fold(Builder::new(), |builder, exchange| {
+                    builder.exchange(exchange)
+                })
+                .version(Version::VersionB1)
+                .primary_url("https://example.com".parse().unwrap())
+                .build()
+        };
+        let bundle = build(vec![variant("en")?, variant("ja")?])?;
+        assert_eq!(bundle.exchanges().len(), 2);
+        bundle.encode()?;
+        assert!(build(vec![exchange()?, exchange()?]).is_err());
+        assert!(build(vec![variant("en")?, variant("en")?]).is_err());
+        assert!(build(vec![variant("en")?, exchange()?]).is_err());
+        assert!(Builder::new()
+            .version(Version::VersionB2)
+            .exchange(exchange()?)
+            .exchange(exchange()?)
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_request_headers() -> Result<()> {
+        let exchange = |request: http::request::Builder| -> Result<Exchange> {
+            let mut response = Response::new(Body::new());
+            response
+                .headers_mut()
+                .insert("variants", HeaderValue::from_static("Accept;text/html"));
+            Ok(Exchange {
+                request: request.uri("https://example.com/").body(())?,
+                response,
+            })
+        };
+        let build = |version: Version, exchange: Exchange| {
+            Builder::new()
+                .version(version)
+                .primary_url("https://example.com/".parse().unwrap())
+                .exchange(exchange)
+                .build()
+        };
+        let accept = || Request::builder().header("accept", "text/html");
+        assert!(build(Version::VersionB1, exchange(accept())?).is_ok());
+        assert!(build(Version::VersionB2, exchange(accept())?).is_err());
+        assert!(build(
+            Version::VersionB1,
+            exchange(accept().header("accept-language", "en"))?
+        )
+        .is_err());
+        assert!(build(
+            Version::VersionB1,
+            exchange(Request::builder().method("POST"))?
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn build_unknown_version() {
+        assert!(Builder::new()
+            .version(Version::Unknown([0x62, 0x39, 0, 0]))
+            .build()
+            .is_err());
+    }
+
     #[tokio::test]
     async fn exchange_builder() -> Result<()> {
         let base_dir = {
@@ -290,6 +623,50 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn detect_manifest() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("index.html"), "<title>App</title>")?;
+        // Not a web app manifest, which has neither start_url nor name.
+        std::fs::write(
+            dir.path().join("manifest.webmanifest"),
+            r#"{"theme_color": "red"}"#,
+        )?;
+        std::fs::write(dir.path().join("manifest.json"), r#"{"start_url": "./"}"#)?;
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("sub/manifest.webmanifest"), "{}")?;
+
+        let dir = dir.path();
+        let builder = |version: Version| async move {
+            Builder::new()
+                .version(version)
+                .primary_url("https://example.com/".parse()?)
+                .exchanges_from_dir(dir, "https://example.com/".parse()?)
+                .await
+        };
+        let bundle = builder(Version::VersionB1).await?.build()?;
+        assert_eq!(
+            bundle.manifest(),
+            &Some("https://example.com/manifest.json".parse()?)
+        );
+        let manifest = bundle.web_app_manifest()?.unwrap();
+        assert_eq!(manifest.start_url, Some("https://example.com/".parse()?));
+        manifest.validate(&bundle)?;
+
+        // The manifest url which is set explicitly is preferred.
+        let manifest_url: Uri = "https://example.com/sub/manifest.webmanifest".parse()?;
+        let bundle = builder(Version::VersionB1)
+            .await?
+            .manifest(manifest_url.clone())
+            .build()?;
+        assert_eq!(bundle.manifest(), &Some(manifest_url));
+
+        // Other versions can't have a manifest.
+        let bundle = builder(Version::VersionB2).await?.build()?;
+        assert_eq!(bundle.manifest(), &None);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn walk() -> Result<()> {
         let base_dir = {
@@ -320,6 +697,6 @@ mod tests {
         exchanges
             .iter()
             .find(|e| e.request.uri() == uri)
-            .context("not fouond")
+            .ok_or_else(|| Error::InvalidBundle(format!("not found: {}", uri)))
     }
 }
@@ -15,28 +15,48 @@
 use crate::builder::Builder;
 use crate::decoder;
 use crate::encoder;
+use crate::integrity_block::{self, IntegrityBlock};
+use crate::manifest::WebAppManifest;
+use crate::options::{DecoderOptions, EncoderOptions, Limits, ParseMode};
 use crate::prelude::*;
+use crate::signatures::{Signatures, SignedSubset};
+use crate::validation::ValidationReport;
+use crate::variants;
+pub use bytes::Bytes;
 pub use http::Uri;
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::fs::File;
 use std::io::Write;
+use std::path::Path;
+use std::sync::OnceLock;
+use url::Url;
 
-pub type Body = Vec<u8>;
+/// The body of a response.
+///
+/// A body shares the underlying buffer of the parsed bundle, so cloning a body
+/// does not copy it.
+pub type Body = Bytes;
 
 pub type Request = http::Request<()>;
 pub type Response = http::Response<Body>;
 
 pub const HEADER_MAGIC_BYTES: [u8; 8] = [0xf0, 0x9f, 0x8c, 0x90, 0xf0, 0x9f, 0x93, 0xa6];
 pub(crate) const VERSION_BYTES_LEN: usize = 4;
-pub(crate) const TOP_ARRAY_LEN: usize = 6;
-pub(crate) const KNOWN_SECTION_NAMES: [&str; 5] =
+pub(crate) const TOP_ARRAY_LEN: usize = 5;
+pub(crate) const TOP_ARRAY_LEN_B1: usize = 6;
+pub(crate) const KNOWN_SECTION_NAMES: [&str; 4] = ["index", "critical", "responses", "primary"];
+pub(crate) const KNOWN_SECTION_NAMES_B1: [&str; 5] =
     ["index", "manifest", "signatures", "critical", "responses"];
 
 /// Represents the version of WebBundle.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Version {
-    /// Version b1, which is used in Google Chrome
+    /// Version b1
     VersionB1,
+    /// Version b2, which is used in Google Chrome
+    VersionB2,
     /// Version 1
     Version1,
     /// Unknown version
@@ -48,8 +68,9 @@ impl Version {
     pub fn bytes(&self) -> &[u8; 4] {
         match self {
             Version::VersionB1 => &[0x62, 0x31, 0, 0],
+            Version::VersionB2 => &[0x62, 0x32, 0, 0],
             Version::Version1 => &[0x31, 0, 0, 0],
-            Version::Unknown(a) => &a,
+            Version::Unknown(a) => a,
         }
     }
 }
@@ -61,13 +82,95 @@ pub struct Exchange {
     pub response: Response,
 }
 
+impl Clone for Exchange {
+    /// Clones this exchange. A body is not copied, but shared.
+    ///
+    /// Extensions of the request and the response are not cloned.
+    fn clone(&self) -> Self {
+        let mut request = Request::new(());
+        *request.method_mut() = self.request.method().clone();
+        *request.uri_mut() = self.request.uri().clone();
+        *request.version_mut() = self.request.version();
+        *request.headers_mut() = self.request.headers().clone();
+        let mut response = Response::new(self.response.body().clone());
+        *response.status_mut() = self.response.status();
+        *response.version_mut() = self.response.version();
+        *response.headers_mut() = self.response.headers().clone();
+        Exchange { request, response }
+    }
+}
+
 /// Represents a WebBundle.
-#[derive(Debug)]
+///
+/// Cloning a bundle is cheap because bodies are shared.
+///
+/// The signatures are not updated when the exchanges are modified, so they
+/// no longer verify afterwards.
+#[derive(Debug, Clone)]
 pub struct Bundle {
     pub(crate) version: Version,
-    pub(crate) primary_url: Uri,
+    pub(crate) primary_url: Option<Uri>,
     pub(crate) manifest: Option<Uri>,
+    pub(crate) signatures: Option<Signatures>,
+    /// The names of the sections which a parser must understand.
+    pub(crate) critical: Vec<String>,
+    /// The sections which this library doesn't understand, as pairs of a name
+    /// and an encoded CBOR item.
+    pub(crate) custom_sections: Vec<(String, Vec<u8>)>,
+    /// The names of the sections in the order of the section table, except
+    /// for the responses section, which the encoder follows. This is empty
+    /// unless the bundle is decoded.
+    pub(crate) section_order: Vec<String>,
+    pub(crate) integrity_block: Option<IntegrityBlock>,
     pub(crate) exchanges: Vec<Exchange>,
+    /// The positions of the exchanges for each URL key, which is built on the
+    /// first lookup and reset when the exchanges are modified.
+    pub(crate) url_index: OnceLock<HashMap<String, Vec<usize>>>,
+    /// Whether several index entries of the decoded bundle share a response,
+    /// which [`encode`](Bundle::encode) keeps.
+    pub(crate) shares_responses: bool,
+}
+
+/// Returns the key by which the given URL is looked up. The scheme and the
+/// host are lowercased, and a default port is dropped.
+pub(crate) fn url_key(uri: &Uri) -> String {
+    let (scheme, authority) = match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => (scheme.to_ascii_lowercase(), authority),
+        _ => return uri.to_string(),
+    };
+    let mut key = format!("{}://", scheme);
+    if let Some((userinfo, _)) = authority.as_str().rsplit_once('@') {
+        key.push_str(userinfo);
+        key.push('@');
+    }
+    key.push_str(&authority.host().to_ascii_lowercase());
+    let default_port = match scheme.as_str() {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    };
+    if let Some(port) = uri.port_u16().filter(|&port| Some(port) != default_port) {
+        key.push_str(&format!(":{}", port));
+    }
+    key.push_str(uri.path_and_query().map_or("/", |path| path.as_str()));
+    key
+}
+
+/// Checks that `exchange` can be added to `others`, the exchanges for the
+/// same URL. Only the variants of a URL in version b1 can share it.
+pub(crate) fn check_same_url<'a>(
+    version: &Version,
+    exchange: &Exchange,
+    others: impl IntoIterator<Item = &'a Exchange>,
+) -> Result<()> {
+    for other in others {
+        ensure!(
+            *version == Version::VersionB1,
+            Error::InvalidBundle(format!("Duplicate URL: {}", exchange.request.uri()))
+        );
+        variants::check_variants(exchange, other)?;
+    }
+    Ok(())
 }
 
 impl Bundle {
@@ -77,7 +180,9 @@ impl Bundle {
     }
 
     /// Gets the primary url.
-    pub fn primary_url(&self) -> &Uri {
+    ///
+    /// A primary url is mandatory in version b1, and optional in other versions.
+    pub fn primary_url(&self) -> &Option<Uri> {
         &self.primary_url
     }
 
@@ -86,36 +191,481 @@ impl Bundle {
         &self.manifest
     }
 
+    /// Parses the web app manifest at the manifest url, if the bundle has the
+    /// exchange for it.
+    pub fn web_app_manifest(&self) -> Result<Option<WebAppManifest>> {
+        let manifest_url = match &self.manifest {
+            Some(manifest_url) => manifest_url,
+            None => return Ok(None),
+        };
+        self.get(manifest_url)
+            .map(|exchange| WebAppManifest::parse(exchange.response.body(), manifest_url))
+            .transpose()
+    }
+
+    /// Gets the signatures.
+    pub fn signatures(&self) -> &Option<Signatures> {
+        &self.signatures
+    }
+
+    /// Verifies the signatures against the exchanges, returning the verified
+    /// signed subsets.
+    ///
+    /// See [`Signatures::verify`].
+    pub fn verify_signatures(&self) -> Result<Vec<SignedSubset>> {
+        self.signatures
+            .as_ref()
+            .ok_or_else(|| Error::Signature("No signatures".to_string()))?
+            .verify(&self.exchanges)
+    }
+
+    /// Gets the names of the critical sections, which a parser must fail to
+    /// parse the bundle if it doesn't understand.
+    pub fn critical_sections(&self) -> &[String] {
+        &self.critical
+    }
+
+    /// Gets the sections which this library doesn't understand, as pairs of a
+    /// name and an encoded CBOR item, in the order of the section table.
+    pub fn custom_sections(&self) -> &[(String, Vec<u8>)] {
+        &self.custom_sections
+    }
+
+    /// Returns the names of the sections which this bundle is encoded into,
+    /// except for the critical section.
+    pub(crate) fn section_names(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        match self.version {
+            Version::VersionB1 => {
+                if self.manifest.is_some() {
+                    names.push("manifest");
+                }
+                if self.signatures.is_some() {
+                    names.push("signatures");
+                }
+            }
+            _ => {
+                if self.primary_url.is_some() {
+                    names.push("primary");
+                }
+            }
+        }
+        names.extend(self.custom_sections.iter().map(|(name, _)| name.as_str()));
+        names.push("index");
+        names.push("responses");
+        names
+    }
+
+    /// Gets the integrity block, which precedes a signed web bundle.
+    ///
+    /// The integrity block is not written by [`encode`](Bundle::encode). Use
+    /// [`encode_signed`](Bundle::encode_signed) to sign the bundle again.
+    pub fn integrity_block(&self) -> &Option<IntegrityBlock> {
+        &self.integrity_block
+    }
+
     /// Gets the exchanges.
     pub fn exchanges(&self) -> &[Exchange] {
         &self.exchanges
     }
 
+    fn url_index(&self) -> &HashMap<String, Vec<usize>> {
+        self.url_index.get_or_init(|| {
+            let mut index = HashMap::<_, Vec<_>>::new();
+            for (i, exchange) in self.exchanges.iter().enumerate() {
+                index
+                    .entry(url_key(exchange.request.uri()))
+                    .or_default()
+                    .push(i);
+            }
+            index
+        })
+    }
+
+    /// Resolves the given URL against the primary url if it is relative.
+    fn resolve(&self, uri: &Uri) -> Option<Uri> {
+        if uri.scheme().is_some() {
+            return Some(uri.clone());
+        }
+        let base = Url::parse(&self.primary_url.as_ref()?.to_string()).ok()?;
+        base.join(&uri.to_string()).ok()?.as_str().parse().ok()
+    }
+
+    /// Returns the positions of the exchanges for the given URL.
+    fn positions(&self, uri: &Uri) -> &[usize] {
+        self.resolve(uri)
+            .and_then(|uri| self.url_index().get(&url_key(&uri)))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Gets the exchange for the given URL. A relative URL is resolved against
+    /// the primary url. The scheme and the host are compared
+    /// case-insensitively, and a default port is ignored.
+    ///
+    /// If the URL has variants, the first one is returned. Use
+    /// [`select`](Bundle::select) to negotiate them.
+    pub fn get(&self, uri: &Uri) -> Option<&Exchange> {
+        self.positions(uri).first().map(|&i| &self.exchanges[i])
+    }
+
+    /// Returns `true` if the bundle has an exchange for the given URL. A
+    /// relative URL is resolved against the primary url.
+    pub fn contains(&self, uri: &Uri) -> bool {
+        !self.positions(uri).is_empty()
+    }
+
+    /// Gets the exchange for the given URL mutably, for example, to rewrite
+    /// the response. If the URL has variants, the first one is returned.
+    pub fn get_mut(&mut self, uri: &Uri) -> Option<&mut Exchange> {
+        let i = *self.positions(uri).first()?;
+        // The request URL may be changed through the returned reference.
+        self.url_index.take();
+        Some(&mut self.exchanges[i])
+    }
+
+    /// Adds the exchange.
+    ///
+    /// Returns an error if the bundle already has an exchange for the URL,
+    /// unless this is a b1 bundle and the exchanges are variants of the URL
+    /// with distinct `Variant-Key` headers, or if the version can't represent
+    /// the request.
+    pub fn insert_exchange(&mut self, exchange: Exchange) -> Result<()> {
+        crate::builder::check_request(&self.version, &exchange)?;
+        let key = url_key(exchange.request.uri());
+        let others = self.url_index().get(&key).into_iter().flatten();
+        check_same_url(
+            &self.version,
+            &exchange,
+            others.map(|&i| &self.exchanges[i]),
+        )?;
+        let i = self.exchanges.len();
+        self.exchanges.push(exchange);
+        if let Some(index) = self.url_index.get_mut() {
+            index.entry(key).or_default().push(i);
+        }
+        Ok(())
+    }
+
+    /// Removes the exchanges for the given URL and returns them. More than one
+    /// exchange is returned if the URL has variants. A relative URL is
+    /// resolved against the primary url.
+    pub fn remove(&mut self, uri: &Uri) -> Vec<Exchange> {
+        let key = match self.resolve(uri) {
+            Some(uri) => url_key(&uri),
+            None => return Vec::new(),
+        };
+        let (removed, exchanges) = std::mem::take(&mut self.exchanges)
+            .into_iter()
+            .partition(|exchange| url_key(exchange.request.uri()) == key);
+        self.exchanges = exchanges;
+        self.url_index.take();
+        removed
+    }
+
+    /// Retains only the exchanges for which the given predicate returns
+    /// `true`, for example, to drop source maps.
+    pub fn retain(&mut self, f: impl FnMut(&Exchange) -> bool) {
+        self.exchanges.retain(f);
+        self.url_index.take();
+    }
+
+    /// Sets the primary url.
+    ///
+    /// Returns an error if the primary url is removed from a b1 bundle, which
+    /// requires it, or from a bundle whose primary section is critical.
+    pub fn set_primary_url(&mut self, primary_url: Option<Uri>) -> Result<()> {
+        if primary_url.is_none() {
+            ensure!(
+                self.version != Version::VersionB1,
+                Error::InvalidBundle("no primary_url".to_string())
+            );
+            ensure!(
+                !self.critical.iter().any(|name| name == "primary"),
+                Error::InvalidBundle("No section for the critical section: primary".to_string())
+            );
+        }
+        self.primary_url = primary_url;
+        Ok(())
+    }
+
+    /// Selects the exchange which best matches the given request.
+    ///
+    /// The request's URL is looked up as [`get`](Bundle::get) does. If several
+    /// exchanges exist for the URL, they are negotiated by their `Variants`
+    /// and `Variant-Key` response headers against the request's headers, such
+    /// as `Accept-Language`.
+    pub fn select(&self, request: &Request) -> Option<&Exchange> {
+        let candidates = self
+            .positions(request.uri())
+            .iter()
+            .map(|&i| &self.exchanges[i])
+            .collect::<Vec<_>>();
+        variants::select(&candidates, request.headers())
+    }
+
     /// Parses the given bytes and returns the parsed Bundle.
-    pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Bundle> {
-        decoder::parse(bytes)
+    ///
+    /// The bodies of the parsed bundle share the given bytes without copying.
+    pub fn from_bytes(bytes: impl Into<Bytes>) -> Result<Bundle> {
+        Bundle::from_bytes_with_limits(bytes, &Limits::default())
+    }
+
+    /// Parses the given bytes with the given resource limits, which should be
+    /// used for a bundle from an untrusted source.
+    pub fn from_bytes_with_limits(bytes: impl Into<Bytes>, limits: &Limits) -> Result<Bundle> {
+        let options = DecoderOptions::default().limits(*limits);
+        let (bundle, report) = Bundle::from_bytes_with_options(bytes, &options)?;
+        for issue in report.issues() {
+            log::warn!("{}", issue);
+        }
+        Ok(bundle)
+    }
+
+    /// Parses the given bytes with the given options, returning the issues
+    /// which are tolerated in the parse mode.
+    pub fn from_bytes_with_options(
+        bytes: impl Into<Bytes>,
+        options: &DecoderOptions,
+    ) -> Result<(Bundle, ValidationReport)> {
+        decoder::parse(bytes.into(), options)
+    }
+
+    /// Parses the given bytes, reporting structural problems which
+    /// [`from_bytes`](Bundle::from_bytes) tolerates, such as a wrong length
+    /// field or a response which no index entry refers to.
+    ///
+    /// Returns an error if the bytes can't be parsed at all.
+    pub fn validate(bytes: impl Into<Bytes>) -> Result<ValidationReport> {
+        let options = DecoderOptions::default().mode(ParseMode::Normal);
+        Ok(Bundle::from_bytes_with_options(bytes, &options)?.1)
+    }
+
+    /// Opens and parses the bundle at the given path.
+    ///
+    /// The file is memory-mapped, and the bodies of the parsed bundle refer to
+    /// the mapped file without copying. The file must not be modified while the
+    /// bundle or any of its bodies is alive.
+    pub fn open(path: impl AsRef<Path>) -> Result<Bundle> {
+        let file = File::open(path)?;
+        // Safety: The caller must not modify the file while it is mapped, as
+        // documented above.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Bundle::from_bytes(Bytes::from_owner(mmap))
     }
 
     /// Encodes this bundle and write the result to the given `write`.
     pub fn write_to<W: Write + Sized>(&self, write: W) -> Result<()> {
-        encoder::encode(&self, write)
+        self.write_to_with_options(write, &self.default_encoder_options())
+    }
+
+    /// Encodes this bundle with the given options and write the result to the
+    /// given `write`.
+    pub fn write_to_with_options<W: Write + Sized>(
+        &self,
+        write: W,
+        options: &EncoderOptions,
+    ) -> Result<()> {
+        encoder::encode(self, write, options)
     }
 
     /// Encodes this bundle.
+    ///
+    /// A bundle decoded from a canonical bundle, for which
+    /// [`validate`](Bundle::validate) reports no issue, is encoded into the
+    /// same bytes. If the decoded bundle shares responses among index entries,
+    /// identical responses are written once, as
+    /// [`EncoderOptions::dedup_responses`] does.
+    ///
+    /// The integrity block of a decoded signed web bundle is not written, so
+    /// only the web bundle which follows it is encoded into the same bytes.
+    /// Use [`encode_signed`](Bundle::encode_signed) to write an integrity
+    /// block.
     pub fn encode(&self) -> Result<Vec<u8>> {
-        encoder::encode_to_vec(&self)
+        self.encode_with_options(&self.default_encoder_options())
+    }
+
+    fn default_encoder_options(&self) -> EncoderOptions {
+        EncoderOptions::default().dedup_responses(self.shares_responses)
+    }
+
+    /// Encodes this bundle with the given options.
+    pub fn encode_with_options(&self, options: &EncoderOptions) -> Result<Vec<u8>> {
+        encoder::encode_to_vec(self, options)
+    }
+
+    /// Encodes this bundle, prepending an integrity block signed by the
+    /// given keys, and write the result to the given `write`.
+    pub fn write_signed_to<W: Write + Sized>(
+        &self,
+        mut write: W,
+        signing_keys: &[ed25519_dalek::SigningKey],
+    ) -> Result<()> {
+        write.write_all(&self.encode_signed(signing_keys)?)?;
+        Ok(())
+    }
+
+    /// Encodes this bundle, prepending an integrity block signed by the
+    /// given keys.
+    pub fn encode_signed(&self, signing_keys: &[ed25519_dalek::SigningKey]) -> Result<Vec<u8>> {
+        let web_bundle = self.encode()?;
+        let mut bytes = integrity_block::sign(&web_bundle, signing_keys)?;
+        bytes.extend_from_slice(&web_bundle);
+        Ok(bytes)
     }
 
     /// Returns a new builder.
     pub fn builder() -> Builder {
         Builder::new()
     }
+
+    /// Returns a builder which has the fields of this bundle, to continue
+    /// building from a decoded bundle.
+    ///
+    /// The signatures and the integrity block are dropped, and the sections
+    /// are encoded in the default order.
+    pub fn into_builder(self) -> Builder {
+        let mut builder = Builder::new().version(self.version);
+        if let Some(primary_url) = self.primary_url {
+            builder = builder.primary_url(primary_url);
+        }
+        if let Some(manifest) = self.manifest {
+            builder = builder.manifest(manifest);
+        }
+        for name in self.critical {
+            builder = builder.critical_section(name);
+        }
+        for (name, bytes) in self.custom_sections {
+            builder = builder.custom_section(name, bytes);
+        }
+        self.exchanges
+            .into_iter()
+            .fold(builder, |builder, exchange| builder.exchange(exchange))
+    }
 }
 
 impl<'a> TryFrom<&'a [u8]> for Bundle {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(bytes: &'a [u8]) -> Result<Self, Self::Error> {
-        Bundle::from_bytes(bytes)
+        Bundle::from_bytes(Bytes::copy_from_slice(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{exchange, variant};
+
+    #[test]
+    fn mutation() -> Result<()> {
+        let bytes = Bundle::builder()
+            .version(Version::Version1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(exchange("https://example.com/", "index")?)
+            .exchange(exchange("https://example.com/a.js.map", "map")?)
+            .build()?
+            .encode()?;
+        let mut bundle = Bundle::from_bytes(bytes)?;
+
+        bundle.insert_exchange(exchange("https://example.com/sw.js", "sw")?)?;
+        assert!(bundle
+            .insert_exchange(exchange("https://example.com/sw.js", "sw")?)
+            .is_err());
+        bundle.retain(|exchange| !exchange.request.uri().path().ends_with(".map"));
+        *bundle
+            .get_mut(&"https://example.com/".parse()?)
+            .unwrap()
+            .response
+            .body_mut() = Body::from("rewritten");
+        let removed = bundle.remove(&"https://example.com/sw.js".parse()?);
+        assert_eq!(removed.len(), 1);
+        assert!(bundle
+            .remove(&"https://example.com/sw.js".parse()?)
+            .is_empty());
+
+        bundle.set_primary_url(None)?;
+        let bundle = Bundle::from_bytes(bundle.encode()?)?;
+        assert_eq!(bundle.primary_url(), &None);
+        assert_eq!(bundle.exchanges().len(), 1);
+        assert_eq!(bundle.exchanges()[0].response.body(), "rewritten");
+
+        // Continue building from the decoded bundle.
+        let bundle = bundle
+            .into_builder()
+            .exchange(exchange("https://example.com/b", "b")?)
+            .build()?;
+        assert_eq!(bundle.version(), &Version::Version1);
+        assert_eq!(bundle.exchanges().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn lookup() -> Result<()> {
+        let mut bundle = Bundle::builder()
+            .version(Version::VersionB2)
+            .primary_url("https://example.com/dir/index.html".parse()?)
+            .exchange(exchange("https://example.com/dir/index.html", "index")?)
+            .exchange(exchange("https://example.com/dir/a.css", "a")?)
+            .exchange(exchange("https://example.com/b.js?q", "b")?)
+            .build()?;
+        let body = |bundle: &Bundle, uri: &str| -> Result<Option<Body>> {
+            Ok(bundle
+                .get(&uri.parse()?)
+                .map(|exchange| exchange.response.body().clone()))
+        };
+        assert_eq!(
+            body(&bundle, "https://example.com/dir/a.css")?.unwrap(),
+            "a"
+        );
+        assert_eq!(body(&bundle, "/dir/a.css")?.unwrap(), "a");
+        assert_eq!(body(&bundle, "/b.js?q")?.unwrap(), "b");
+        assert_eq!(body(&bundle, "/b.js")?, None);
+        assert!(bundle.contains(&"https://example.com/dir/index.html".parse()?));
+        assert!(!bundle.contains(&"https://example.org/dir/index.html".parse()?));
+        // The scheme and the host are case-insensitive, and a default port is
+        // ignored.
+        assert!(bundle.contains(&"HTTPS://Example.COM:443/dir/a.css".parse()?));
+        assert!(!bundle.contains(&"https://example.com:8443/dir/a.css".parse()?));
+        assert!(!bundle.contains(&"https://example.com/DIR/a.css".parse()?));
+        for uri in ["HTTPS://Example.COM:443/dir/a.css", "/dir/a.css"] {
+            let request = Request::get(uri).body(())?;
+            assert_eq!(bundle.select(&request).unwrap().response.body(), "a");
+        }
+
+        // The index follows the modifications.
+        bundle.insert_exchange(exchange("https://example.com/c", "c")?)?;
+        assert_eq!(body(&bundle, "/c")?.unwrap(), "c");
+        *bundle.get_mut(&"/c".parse()?).unwrap().request.uri_mut() =
+            "https://example.com/d".parse()?;
+        assert!(!bundle.contains(&"/c".parse()?));
+        assert_eq!(body(&bundle, "/d")?.unwrap(), "c");
+        bundle.retain(|exchange| exchange.request.uri().path() != "/d");
+        assert!(!bundle.contains(&"/d".parse()?));
+
+        // A relative URL can't be resolved without a primary url.
+        bundle.set_primary_url(None)?;
+        assert!(!bundle.contains(&"/dir/a.css".parse()?));
+        assert!(bundle.contains(&"https://example.com/dir/a.css".parse()?));
+        Ok(())
+    }
+
+    #[test]
+    fn insert_variants() -> Result<()> {
+        let variant = |key| variant("https://example.com/", "Accept-Language;en;ja", key);
+        let mut bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(variant("en")?)
+            .build()?;
+        assert!(bundle.set_primary_url(None).is_err());
+        bundle.insert_exchange(variant("ja")?)?;
+        assert!(bundle.insert_exchange(variant("ja")?).is_err());
+        assert!(bundle
+            .insert_exchange(exchange("https://example.com/", "plain")?)
+            .is_err());
+        assert_eq!(bundle.remove(&"https://example.com/".parse()?).len(), 2);
+        Ok(())
     }
 }
@@ -0,0 +1,604 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal CBOR reader and writer for the bundle format.
+//!
+//! [`Writer`] only produces deterministically encoded CBOR: arguments are in
+//! the shortest form, lengths are definite and map keys are sorted.
+//! [`Reader`] reports the offset of a malformed item, and borrows strings
+//! from its buffer. Indefinite lengths are not supported.
+//!
+//! See [RFC 8949](https://www.rfc-editor.org/rfc/rfc8949.html).
+
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+
+/// The maximum nesting depth of items, so that a crafted input can't overflow
+/// the stack.
+const MAX_DEPTH: usize = 16;
+
+/// The major type of a data item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Type {
+    UnsignedInteger,
+    NegativeInteger,
+    Bytes,
+    Text,
+    Array,
+    Map,
+    Tag,
+    Simple,
+}
+
+impl Type {
+    fn from_major_type(major_type: u8) -> Type {
+        match major_type {
+            0 => Type::UnsignedInteger,
+            1 => Type::NegativeInteger,
+            2 => Type::Bytes,
+            3 => Type::Text,
+            4 => Type::Array,
+            5 => Type::Map,
+            6 => Type::Tag,
+            _ => Type::Simple,
+        }
+    }
+
+    fn major_type(self) -> u8 {
+        self as u8
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::UnsignedInteger => "an unsigned integer",
+            Type::NegativeInteger => "a negative integer",
+            Type::Bytes => "a byte string",
+            Type::Text => "a text string",
+            Type::Array => "an array",
+            Type::Map => "a map",
+            Type::Tag => "a tag",
+            Type::Simple => "a simple value or a float",
+        })
+    }
+}
+
+/// The head of a data item, which is its major type and its argument.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Head {
+    pub(crate) ty: Type,
+    /// The argument, or `None` for an indefinite length.
+    pub(crate) argument: Option<u64>,
+    /// The number of bytes of the head.
+    pub(crate) len: usize,
+}
+
+impl Head {
+    /// Parses the head at the beginning of `buf`. Returns `None` if it is
+    /// truncated or uses a reserved value, or if it is a simple value below 32
+    /// in two bytes, which is not well-formed (RFC 8949, Section 3.3).
+    fn parse(buf: &[u8]) -> Option<Head> {
+        let initial_byte = *buf.first()?;
+        let ty = Type::from_major_type(initial_byte >> 5);
+        let (argument, len) = match initial_byte & 0x1f {
+            info @ 0..=23 => (Some(u64::from(info)), 1),
+            info @ 24..=27 => {
+                let size = 1 << (info - 24);
+                let argument = buf
+                    .get(1..1 + size)?
+                    .iter()
+                    .fold(0u64, |argument, &b| (argument << 8) | u64::from(b));
+                (Some(argument), 1 + size)
+            }
+            31 => (None, 1),
+            _ => return None,
+        };
+        if ty == Type::Simple && len == 2 && argument? < 32 {
+            return None;
+        }
+        Some(Head { ty, argument, len })
+    }
+
+    /// Returns true if the argument is in the shortest form. Floats are
+    /// exempt.
+    fn is_shortest(&self) -> bool {
+        let argument = match self.argument {
+            Some(argument) => argument,
+            None => return false,
+        };
+        match self.len {
+            1 => true,
+            _ if self.ty == Type::Simple && self.len > 2 => true,
+            2 => argument >= 24,
+            3 => argument > 0xff,
+            5 => argument > 0xffff,
+            _ => argument > 0xffff_ffff,
+        }
+    }
+}
+
+/// Writes deterministically encoded CBOR items, counting the written bytes.
+pub(crate) struct Writer<W> {
+    write: W,
+    count: usize,
+}
+
+impl Writer<Vec<u8>> {
+    pub(crate) fn new_vec() -> Self {
+        Writer::new(Vec::new())
+    }
+}
+
+impl<W: Write> Writer<W> {
+    pub(crate) fn new(write: W) -> Self {
+        Writer { write, count: 0 }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.write
+    }
+
+    /// Writes bytes which are already encoded.
+    pub(crate) fn write_raw_bytes(&mut self, bytes: &[u8]) -> Result<&mut Self> {
+        self.write.write_all(bytes)?;
+        self.count += bytes.len();
+        Ok(self)
+    }
+
+    /// Writes a head, whose argument is in the shortest form.
+    fn write_head(&mut self, ty: Type, argument: u64) -> Result<&mut Self> {
+        let initial_byte = ty.major_type() << 5;
+        let bytes = argument.to_be_bytes();
+        match argument {
+            0..=23 => self.write_raw_bytes(&[initial_byte | argument as u8]),
+            24..=0xff => self.write_raw_bytes(&[initial_byte | 24, argument as u8]),
+            0x100..=0xffff => self.write_raw_bytes(&[initial_byte | 25, bytes[6], bytes[7]]),
+            0x1_0000..=0xffff_ffff => {
+                self.write_raw_bytes(&[initial_byte | 26])?;
+                self.write_raw_bytes(&bytes[4..])
+            }
+            _ => {
+                self.write_raw_bytes(&[initial_byte | 27])?;
+                self.write_raw_bytes(&bytes)
+            }
+        }
+    }
+
+    pub(crate) fn write_unsigned_integer(&mut self, value: u64) -> Result<&mut Self> {
+        self.write_head(Type::UnsignedInteger, value)
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: impl AsRef<[u8]>) -> Result<&mut Self> {
+        let bytes = bytes.as_ref();
+        self.write_head(Type::Bytes, bytes.len() as u64)?;
+        self.write_raw_bytes(bytes)
+    }
+
+    pub(crate) fn write_text(&mut self, text: impl AsRef<str>) -> Result<&mut Self> {
+        let text = text.as_ref();
+        self.write_head(Type::Text, text.len() as u64)?;
+        self.write_raw_bytes(text.as_bytes())
+    }
+
+    /// Writes the head of an array of `len` items, which must follow.
+    pub(crate) fn write_array(&mut self, len: u64) -> Result<&mut Self> {
+        self.write_head(Type::Array, len)
+    }
+
+    /// Writes the head of a map of `len` entries, which must follow in the
+    /// sorted order. Use [`write_map_entries`](Writer::write_map_entries) to
+    /// sort them.
+    pub(crate) fn write_map(&mut self, len: u64) -> Result<&mut Self> {
+        self.write_head(Type::Map, len)
+    }
+
+    /// Writes a map of encoded keys and values, sorting the keys in the
+    /// bytewise lexicographic order of their encodings. Duplicate keys are an
+    /// error.
+    pub(crate) fn write_map_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<&mut Self> {
+        let mut map = BTreeMap::new();
+        for (key, value) in entries {
+            if map.contains_key(&key) {
+                return Err(Error::InvalidBundle(format!(
+                    "Duplicate map key: {}",
+                    Reader::new(&key, 0).describe()
+                )));
+            }
+            map.insert(key, value);
+        }
+        self.write_map(map.len() as u64)?;
+        for (key, value) in map {
+            self.write_raw_bytes(&key)?;
+            self.write_raw_bytes(&value)?;
+        }
+        Ok(self)
+    }
+}
+
+/// Reads CBOR items from a buffer. Byte strings and text strings are borrowed
+/// from the buffer.
+pub(crate) struct Reader<T> {
+    buf: T,
+    pos: usize,
+    /// The offset of the buffer in the input, which is used in errors.
+    base_offset: u64,
+}
+
+impl<T> Reader<T> {
+    pub(crate) fn new(buf: T, base_offset: u64) -> Self {
+        Reader {
+            buf,
+            pos: 0,
+            base_offset,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.buf
+    }
+}
+
+impl<T: AsRef<[u8]>> Reader<T> {
+    pub(crate) fn buf(&self) -> &[u8] {
+        self.buf.as_ref()
+    }
+
+    pub(crate) fn base_offset(&self) -> u64 {
+        self.base_offset
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn set_position(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Returns the number of bytes after the current position.
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf().len().saturating_sub(self.pos)
+    }
+
+    /// Returns the current offset in the input.
+    pub(crate) fn offset(&self) -> u64 {
+        self.base_offset + self.pos as u64
+    }
+
+    pub(crate) fn malformed(&self, message: impl Into<String>) -> Error {
+        Error::malformed(Some(self.offset()), message)
+    }
+
+    /// Parses the head at the current position without consuming it.
+    pub(crate) fn peek_head(&self) -> Result<Head> {
+        if self.remaining() == 0 {
+            return Err(self.malformed("Unexpected end of input"));
+        }
+        Head::parse(&self.buf()[self.pos..]).ok_or_else(|| self.malformed("Invalid head"))
+    }
+
+    /// Reads the head of an item of the given type, returning its argument.
+    fn read_head(&mut self, ty: Type) -> Result<u64> {
+        let head = self.peek_head()?;
+        if head.ty != ty {
+            return Err(self.malformed(format!("Expected {}, found {}", ty, head.ty)));
+        }
+        let argument = head
+            .argument
+            .ok_or_else(|| self.malformed("Indefinite lengths are not supported"))?;
+        self.pos += head.len;
+        Ok(argument)
+    }
+
+    pub(crate) fn unsigned_integer(&mut self) -> Result<u64> {
+        self.read_head(Type::UnsignedInteger)
+    }
+
+    /// Reads the length of an array.
+    pub(crate) fn array(&mut self) -> Result<u64> {
+        self.read_head(Type::Array)
+    }
+
+    /// Reads the number of entries of a map.
+    pub(crate) fn map(&mut self) -> Result<u64> {
+        self.read_head(Type::Map)
+    }
+
+    /// Reads a string of the given type, returning its range in the buffer.
+    /// The length is checked against the buffer before anything is allocated.
+    fn string_range(&mut self, ty: Type) -> Result<Range<usize>> {
+        let start = self.pos;
+        let len = self.read_head(ty)?;
+        let range = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.remaining())
+            .map(|len| self.pos..self.pos + len);
+        match range {
+            Some(range) => {
+                self.pos = range.end;
+                Ok(range)
+            }
+            None => {
+                self.pos = start;
+                Err(Error::OutOfRange {
+                    offset: self.offset(),
+                    length: len,
+                })
+            }
+        }
+    }
+
+    /// Reads a byte string, returning its range in the buffer.
+    pub(crate) fn bytes_range(&mut self) -> Result<Range<usize>> {
+        self.string_range(Type::Bytes)
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&[u8]> {
+        let range = self.bytes_range()?;
+        Ok(&self.buf()[range])
+    }
+
+    pub(crate) fn text(&mut self) -> Result<&str> {
+        let offset = self.offset();
+        let range = self.string_range(Type::Text)?;
+        std::str::from_utf8(&self.buf.as_ref()[range])
+            .map_err(|_| Error::malformed(Some(offset), "Invalid UTF-8 in a text string"))
+    }
+
+    /// Skips an item without building it.
+    pub(crate) fn skip(&mut self) -> Result<()> {
+        self.skip_with_depth(0)
+    }
+
+    fn skip_with_depth(&mut self, depth: usize) -> Result<()> {
+        ensure!(depth < MAX_DEPTH, self.malformed("Too deeply nested"));
+        let head = self.peek_head()?;
+        match head.ty {
+            Type::Bytes | Type::Text => {
+                self.string_range(head.ty)?;
+            }
+            Type::Array | Type::Map => {
+                let len = self.read_head(head.ty)?;
+                let items = if head.ty == Type::Map { 2 } else { 1 };
+                for _ in 0..len {
+                    for _ in 0..items {
+                        self.skip_with_depth(depth + 1)?;
+                    }
+                }
+            }
+            Type::Tag => {
+                self.read_head(head.ty)?;
+                self.skip_with_depth(depth + 1)?;
+            }
+            _ => {
+                self.read_head(head.ty)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes the item at the current position, for error messages.
+    fn describe(&mut self) -> String {
+        let pos = self.pos;
+        let description = match self.peek_head() {
+            Ok(head) if head.ty == Type::Text => self.text().map(|text| format!("{:?}", text)),
+            Ok(head) if head.ty == Type::Bytes => self
+                .bytes()
+                .map(|bytes| format!("{:?}", String::from_utf8_lossy(bytes))),
+            Ok(head) => Ok(head.ty.to_string()),
+            Err(err) => Err(err),
+        };
+        self.pos = pos;
+        description.unwrap_or_else(|_| "a malformed item".to_string())
+    }
+}
+
+/// Returns the offset of the first item which is not deterministically
+/// encoded in the CBOR item at the beginning of `buf`, or an error if it is
+/// malformed.
+///
+/// See [Core Deterministic Encoding Requirements](https://www.rfc-editor.org/rfc/rfc8949.html#section-4.2.1).
+pub(crate) fn non_canonical_offset(buf: &[u8]) -> std::result::Result<Option<usize>, ()> {
+    check_canonical(buf, &mut 0, 0)
+}
+
+fn check_canonical(
+    buf: &[u8],
+    pos: &mut usize,
+    depth: usize,
+) -> std::result::Result<Option<usize>, ()> {
+    if depth >= MAX_DEPTH {
+        return Err(());
+    }
+    let start = *pos;
+    let head = Head::parse(buf.get(start..).ok_or(())?).ok_or(())?;
+    if !head.is_shortest() {
+        return Ok(Some(start));
+    }
+    let argument = head.argument.ok_or(())?;
+    *pos = start + head.len;
+    match head.ty {
+        Type::Bytes | Type::Text => {
+            let end = usize::try_from(argument)
+                .ok()
+                .and_then(|len| pos.checked_add(len))
+                .filter(|&end| end <= buf.len())
+                .ok_or(())?;
+            *pos = end;
+        }
+        Type::Array => {
+            for _ in 0..argument {
+                if let Some(offset) = check_canonical(buf, pos, depth + 1)? {
+                    return Ok(Some(offset));
+                }
+            }
+        }
+        // Keys must be sorted in the bytewise lexicographic order.
+        Type::Map => {
+            let mut previous_key: Option<&[u8]> = None;
+            for _ in 0..argument {
+                let key_start = *pos;
+                if let Some(offset) = check_canonical(buf, pos, depth + 1)? {
+                    return Ok(Some(offset));
+                }
+                let key = &buf[key_start..*pos];
+                if previous_key.is_some_and(|previous_key| previous_key >= key) {
+                    return Ok(Some(key_start));
+                }
+                previous_key = Some(key);
+                if let Some(offset) = check_canonical(buf, pos, depth + 1)? {
+                    return Ok(Some(offset));
+                }
+            }
+        }
+        Type::Tag => return check_canonical(buf, pos, depth + 1),
+        _ => {}
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(write: impl FnOnce(&mut Writer<Vec<u8>>) -> Result<()>) -> Result<Vec<u8>> {
+        let mut writer = Writer::new_vec();
+        write(&mut writer)?;
+        Ok(writer.into_inner())
+    }
+
+    #[test]
+    fn shortest_form() -> Result<()> {
+        for (value, expected) in [
+            (0, &[0x00][..]),
+            (23, &[0x17]),
+            (24, &[0x18, 0x18]),
+            (0xff, &[0x18, 0xff]),
+            (0x100, &[0x19, 0x01, 0x00]),
+            (0xffff, &[0x19, 0xff, 0xff]),
+            (0x1_0000, &[0x1a, 0x00, 0x01, 0x00, 0x00]),
+            (
+                0x1_0000_0000,
+                &[0x1b, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00],
+            ),
+        ] {
+            let bytes = encode(|w| w.write_unsigned_integer(value).map(|_| ()))?;
+            assert_eq!(bytes, expected);
+            assert_eq!(non_canonical_offset(&bytes), Ok(None));
+            assert_eq!(Reader::new(&bytes, 0).unsigned_integer()?, value);
+        }
+        // Not in the shortest form.
+        assert_eq!(non_canonical_offset(&[0x18, 0x17]), Ok(Some(0)));
+        assert_eq!(
+            non_canonical_offset(&[0x82, 0x00, 0x19, 0x00, 0xff]),
+            Ok(Some(2))
+        );
+        // Floats are exempt.
+        assert_eq!(non_canonical_offset(&[0xf9, 0x00, 0x00]), Ok(None));
+        // A simple value below 32 in two bytes is not well-formed.
+        assert_eq!(non_canonical_offset(&[0xf8, 0x14]), Err(()));
+        assert_eq!(non_canonical_offset(&[0xf8, 0x20]), Ok(None));
+        // Indefinite lengths.
+        assert_eq!(non_canonical_offset(&[0x9f, 0xff]), Ok(Some(0)));
+        // Truncated.
+        assert_eq!(non_canonical_offset(&[0x82, 0x01]), Err(()));
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_map() -> Result<()> {
+        let key = |key: &str| encode(|w| w.write_text(key).map(|_| ()));
+        let bytes = encode(|w| {
+            w.write_map_entries(vec![
+                (key("bb")?, vec![0x01]),
+                (key("c")?, vec![0x02]),
+                (key("a")?, vec![0x03]),
+            ])?;
+            Ok(())
+        })?;
+        // Shorter keys come first, because their heads are smaller.
+        assert_eq!(
+            bytes,
+            [0xa3, 0x61, b'a', 0x03, 0x61, b'c', 0x02, 0x62, b'b', b'b', 0x01]
+        );
+        assert_eq!(non_canonical_offset(&bytes), Ok(None));
+        // Unsorted keys.
+        assert_eq!(
+            non_canonical_offset(&[0xa2, 0x61, b'c', 0x00, 0x61, b'a', 0x00]),
+            Ok(Some(4))
+        );
+        assert!(encode(|w| {
+            w.write_map_entries(vec![(key("a")?, vec![0x00]), (key("a")?, vec![0x01])])?;
+            Ok(())
+        })
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reader() -> Result<()> {
+        let bytes = encode(|w| {
+            w.write_array(3)?
+                .write_bytes(b"abc")?
+                .write_text("def")?
+                .write_map(1)?
+                .write_unsigned_integer(1)?
+                .write_array(0)?;
+            Ok(())
+        })?;
+        let mut reader = Reader::new(&bytes, 10);
+        assert_eq!(reader.array()?, 3);
+        // Strings are borrowed from the buffer.
+        let range = reader.bytes_range()?;
+        assert_eq!(&bytes[range], b"abc");
+        assert_eq!(reader.text()?, "def");
+        let position = reader.position();
+        reader.skip()?;
+        assert_eq!(reader.remaining(), 0);
+
+        // Errors have the offset in the input.
+        reader.set_position(position);
+        match reader.bytes() {
+            Err(Error::MalformedCbor { offset, message }) => {
+                assert_eq!(offset, Some(10 + position as u64));
+                assert_eq!(message, "Expected a byte string, found a map");
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(matches!(
+            Reader::new(&bytes[..bytes.len() - 1], 0).skip(),
+            Err(Error::MalformedCbor { .. })
+        ));
+        // A crafted length doesn't allocate.
+        assert!(matches!(
+            Reader::new(&[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], 0).bytes(),
+            Err(Error::OutOfRange { offset: 0, .. })
+        ));
+        assert!(Reader::new(&[0x9f, 0xff], 0).array().is_err());
+        Ok(())
+    }
+}
@@ -13,123 +13,537 @@
 // limitations under the License.
 
 use crate::bundle::{self, Bundle, Exchange, Request, Response, Uri, Version};
+use crate::cbor::{self, Reader};
+use crate::integrity_block::{self, IntegrityBlock, IntegritySignature};
+use crate::options::{DecoderOptions, HeaderPolicy, Limits, ParseMode};
 use crate::prelude::*;
-use cbor_event::Len;
+use crate::signatures::{
+    Authority, ResourceIntegrity, Signatures, SignedSubset, SubsetHash, VouchedSubset,
+};
+use crate::validation::{self, Issue, ValidationReport};
+use crate::variants::Variants;
+use bytes::Bytes;
 use http::{
     header::{HeaderMap, HeaderName, HeaderValue},
     StatusCode,
 };
 use std::collections::HashSet;
-use std::convert::TryInto;
-use std::io::Cursor;
+use std::convert::{TryFrom, TryInto};
 
-pub(crate) fn parse(bytes: impl AsRef<[u8]>) -> Result<Bundle> {
-    Decoder::new(bytes).decode()
+/// Parses a bundle, returning the issues which are tolerated in the given
+/// parse mode.
+pub(crate) fn parse(bytes: Bytes, options: &DecoderOptions) -> Result<(Bundle, ValidationReport)> {
+    let (bundle, issues) = if integrity_block::has_integrity_block(&bytes) {
+        let (signature_stack, length) = parse_integrity_block(&bytes)?;
+        let web_bundle = bytes.slice(length as usize..);
+        let mut decoder =
+            Decoder::with_base_offset(web_bundle.clone(), length).with_options(*options);
+        let mut bundle = decoder.decode()?;
+        bundle.integrity_block = Some(IntegrityBlock::new(signature_stack, &web_bundle));
+        (bundle, decoder.issues)
+    } else {
+        let mut decoder = Decoder::new(bytes).with_options(*options);
+        (decoder.decode()?, decoder.issues)
+    };
+    Ok((bundle, ValidationReport { issues }))
 }
 
-#[derive(Debug)]
-struct SectionOffset {
-    name: String,
+/// Parses the integrity block at the beginning of the given bytes, returning
+/// the signature stack and the length of the integrity block.
+pub(crate) fn parse_integrity_block(bytes: &[u8]) -> Result<(Vec<IntegritySignature>, u64)> {
+    let mut decoder = Decoder::new(bytes);
+    let signature_stack = decoder.read_integrity_block()?;
+    Ok((signature_stack, decoder.position()))
+}
+
+/// Parses the header of a bundle, returning the section offsets.
+pub(crate) fn parse_section_offsets(bytes: &[u8]) -> Result<Vec<SectionOffset>> {
+    Ok(Decoder::new(bytes).read_header()?.section_offsets)
+}
+
+/// Parses the metadata of a bundle. The given bytes must contain every section
+/// before the responses section.
+pub(crate) fn parse_metadata(bytes: &[u8], options: &DecoderOptions) -> Result<Metadata> {
+    Decoder::new(bytes).with_options(*options).read_metadata()
+}
+
+/// Parses the response for `uri`, which is located at `offset` in a bundle.
+pub(crate) fn parse_response(
+    bytes: Bytes,
     offset: u64,
-    length: u64,
+    uri: &Uri,
+    options: &DecoderOptions,
+) -> Result<Response> {
+    let mut decoder = Decoder::with_base_offset(bytes, offset).with_options(*options);
+    let mut response = decoder.read_response()?;
+    decoder.check_response_headers(uri, &mut response)?;
+    Ok(response)
+}
+
+pub(crate) fn parse_signed_subset(bytes: &[u8]) -> Result<SignedSubset> {
+    Decoder::new(bytes).read_signed_subset()
+}
+
+/// Checks that `bytes` is exactly one well-formed CBOR item.
+pub(crate) fn check_cbor_item(bytes: &[u8]) -> Result<()> {
+    let mut decoder = Decoder::new(bytes);
+    decoder.skip_value()?;
+    ensure!(
+        decoder.remaining() == 0,
+        decoder.malformed("Unexpected bytes after a CBOR item")
+    );
+    Ok(())
 }
 
 #[derive(Debug)]
-struct ResponseLocation {
-    offset: u64,
-    length: u64,
+pub(crate) struct SectionOffset {
+    pub(crate) name: String,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResponseLocation {
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
 }
 
 impl ResponseLocation {
-    pub fn new(responses_section_offset: u64, offset: u64, length: u64) -> ResponseLocation {
-        ResponseLocation {
-            offset: responses_section_offset + offset,
+    /// Returns `None` if the offset overflows.
+    pub fn new(
+        responses_section_offset: u64,
+        offset: u64,
+        length: u64,
+    ) -> Option<ResponseLocation> {
+        Some(ResponseLocation {
+            offset: responses_section_offset.checked_add(offset)?,
             length,
-        }
+        })
     }
 }
 
 #[derive(Debug)]
-struct RequestEntry {
-    request: Request,
-    response_location: ResponseLocation,
+pub(crate) struct RequestEntry {
+    pub(crate) request: Request,
+    pub(crate) response_location: ResponseLocation,
+}
+
+/// Returns true if several index entries share a response.
+fn shares_responses(requests: &[RequestEntry]) -> bool {
+    let mut locations = HashSet::new();
+    !requests.iter().all(|entry| {
+        locations.insert((
+            entry.response_location.offset,
+            entry.response_location.length,
+        ))
+    })
 }
 
 #[derive(Debug)]
-struct Metadata {
+pub(crate) struct Metadata {
+    pub(crate) version: Version,
+    pub(crate) primary_url: Option<Uri>,
+    pub(crate) requests: Vec<RequestEntry>,
+    pub(crate) manifest: Option<Manifest>,
+    pub(crate) signatures: Option<Signatures>,
+    pub(crate) critical: Vec<String>,
+    pub(crate) custom_sections: Vec<(String, Vec<u8>)>,
+    pub(crate) section_offsets: Vec<SectionOffset>,
+}
+
+#[derive(Debug)]
+struct Header {
     version: Version,
-    primary_url: Uri,
+    primary_url: Option<Uri>,
     section_offsets: Vec<SectionOffset>,
+}
+
+#[derive(Debug, Default)]
+struct Sections {
+    primary_url: Option<Uri>,
     requests: Vec<RequestEntry>,
     manifest: Option<Manifest>,
+    signatures: Option<Signatures>,
+    critical: Vec<String>,
+    custom: Vec<(String, Vec<u8>)>,
 }
 
-type Deserializer<R> = cbor_event::de::Deserializer<R>;
-
 struct Decoder<T> {
-    de: Deserializer<Cursor<T>>,
+    de: Reader<T>,
+    options: DecoderOptions,
+    /// The problems found so far, which don't prevent parsing.
+    issues: Vec<Issue>,
 }
 
 impl<T> Decoder<T> {
     fn new(buf: T) -> Self {
+        Decoder::with_base_offset(buf, 0)
+    }
+
+    fn with_base_offset(buf: T, base_offset: u64) -> Self {
         Decoder {
-            de: Deserializer::from(Cursor::new(buf)),
+            de: Reader::new(buf, base_offset),
+            options: DecoderOptions::default(),
+            issues: Vec::new(),
         }
     }
+
+    fn with_options(mut self, options: DecoderOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    fn limits(&self) -> &Limits {
+        &self.options.limits
+    }
+
+    fn is_lenient(&self) -> bool {
+        self.options.mode == ParseMode::Lenient
+    }
+
+    /// Records an issue, which is an error in the strict mode.
+    fn report(&mut self, issue: Issue) -> Result<()> {
+        if self.options.mode == ParseMode::Strict {
+            return Err(Error::Nonconforming(issue));
+        }
+        self.issues.push(issue);
+        Ok(())
+    }
+
+    /// Merges the issues found by a nested decoder.
+    fn merge<U>(&mut self, nested: &mut Decoder<U>) {
+        self.issues.append(&mut nested.issues);
+    }
 }
 
 type Manifest = Uri;
 
-impl<T: AsRef<[u8]>> Decoder<T> {
+impl Decoder<Bytes> {
     fn decode(&mut self) -> Result<Bundle> {
+        self.check_canonical_item()?;
         let metadata = self.read_metadata()?;
-        Ok(Bundle {
+        // The responses section is the last section.
+        let responses_section = metadata.section_offsets.last().unwrap();
+        if let Err(err) = self.validate_responses(responses_section, &metadata.requests) {
+            self.report(Issue::InvalidResponses {
+                offset: self.de.base_offset() + responses_section.offset,
+                message: err.to_string(),
+            })?;
+        }
+        self.validate_length(responses_section.offset + responses_section.length)?;
+        let shares_responses = shares_responses(&metadata.requests);
+        let bundle = Bundle {
             version: metadata.version,
             primary_url: metadata.primary_url,
             exchanges: self.read_responses(metadata.requests)?,
             manifest: metadata.manifest,
-        })
+            signatures: metadata.signatures,
+            critical: metadata.critical,
+            custom_sections: metadata.custom_sections,
+            section_order: metadata
+                .section_offsets
+                .iter()
+                .map(|section| section.name.clone())
+                .filter(|name| name != "responses")
+                .collect(),
+            integrity_block: None,
+            url_index: Default::default(),
+            shares_responses,
+        };
+        self.validate_manifest(&bundle)?;
+        Ok(bundle)
     }
 
-    fn read_metadata(&mut self) -> Result<Metadata> {
+    /// Checks the web app manifest which the manifest section names, if the
+    /// bundle has it.
+    fn validate_manifest(&mut self, bundle: &Bundle) -> Result<()> {
+        let manifest_url = match &bundle.manifest {
+            Some(manifest_url) => manifest_url,
+            None => return Ok(()),
+        };
+        let result = bundle
+            .web_app_manifest()
+            .and_then(|manifest| manifest.map_or(Ok(()), |manifest| manifest.validate(bundle)));
+        if let Err(err) = result {
+            self.report(Issue::InvalidManifest {
+                url: manifest_url.to_string(),
+                message: match err {
+                    Error::InvalidManifest(message) => message,
+                    err => err.to_string(),
+                },
+            })?;
+        }
+        Ok(())
+    }
+
+    fn new_bytes_decoder_from_range(&self, offset: u64, length: u64) -> Result<Decoder<Bytes>> {
+        let range = self.checked_range(offset, length)?;
+        Ok(Decoder::with_base_offset(
+            self.de.get_ref().slice(range),
+            self.de.base_offset() + offset,
+        )
+        .with_options(self.options))
+    }
+
+    /// Checks that the responses section consists of exactly the responses
+    /// which the index section refers to.
+    fn validate_responses(
+        &mut self,
+        responses_section: &SectionOffset,
+        requests: &[RequestEntry],
+    ) -> Result<()> {
+        let mut issues = Vec::new();
+        let mut decoder =
+            self.new_decoder_from_range(responses_section.offset, responses_section.length)?;
+        let section = &self.inner_buf()
+            [self.checked_range(responses_section.offset, responses_section.length)?];
+        let responses_len = decoder.read_array_len()?;
+        let mut locations = HashSet::new();
+        // The responses by their bytes, to find the identical ones which are
+        // not shared in a bundle which shares responses.
+        let mut responses = shares_responses(requests).then(HashSet::new);
+        for _ in 0..responses_len {
+            let start = decoder.position();
+            decoder.skip_value()?;
+            let end = decoder.position();
+            locations.insert((responses_section.offset + start, end - start));
+            if let Some(responses) = &mut responses {
+                if !responses.insert(&section[start as usize..end as usize]) {
+                    issues.push(Issue::DuplicateResponse {
+                        offset: self.de.base_offset() + responses_section.offset + start,
+                    });
+                }
+            }
+        }
+        if decoder.position() != responses_section.length {
+            issues.push(Issue::SectionLengthMismatch {
+                name: responses_section.name.clone(),
+                declared: responses_section.length,
+                actual: decoder.position(),
+            });
+        }
+
+        // Several index entries may share the same response.
+        let mut index_locations = requests
+            .iter()
+            .map(|entry| {
+                (
+                    entry.response_location.offset,
+                    entry.response_location.length,
+                )
+            })
+            .collect::<Vec<_>>();
+        index_locations.sort_unstable();
+        index_locations.dedup();
+        if responses_len != index_locations.len() as u64 {
+            issues.push(Issue::ResponsesCountMismatch {
+                responses: responses_len,
+                index: index_locations.len() as u64,
+            });
+        }
+        for (offset, length) in index_locations {
+            if !locations.contains(&(offset, length)) {
+                issues.push(Issue::DanglingIndexEntry {
+                    offset: self.de.base_offset() + offset,
+                    length,
+                });
+            }
+        }
+        for issue in issues {
+            self.report(issue)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the trailing length field, which is at `offset`.
+    fn validate_length(&mut self, offset: u64) -> Result<()> {
+        self.de
+            .set_position(usize::try_from(offset).unwrap_or(usize::MAX));
+        let field_offset = self.offset();
+        let declared = match self.de.bytes().map(<[u8; 8]>::try_from) {
+            Ok(Ok(bytes)) => u64::from_be_bytes(bytes),
+            _ => {
+                return self.report(Issue::InvalidLengthField {
+                    offset: field_offset,
+                })
+            }
+        };
+        let actual = self.inner_buf().len() as u64;
+        if declared != actual {
+            self.report(Issue::LengthMismatch { declared, actual })?;
+        }
+        if self.remaining() > 0 {
+            self.report(Issue::TrailingBytes {
+                offset: self.offset(),
+                length: self.remaining(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads the responses in the order of the responses section, which is not
+    /// always the order of the index section.
+    fn read_responses(&mut self, mut requests: Vec<RequestEntry>) -> Result<Vec<Exchange>> {
+        requests.sort_by_key(|request| request.response_location.offset);
+        let mut exchanges = Vec::with_capacity(requests.len());
+        // The location of the last exchange, whose response is reused by the
+        // following index entries which share it.
+        let mut last_location = None;
+        for RequestEntry {
+            request,
+            response_location: ResponseLocation { offset, length },
+        } in requests
+        {
+            let response = match exchanges.last() {
+                Some(exchange) if last_location == Some((offset, length)) => {
+                    let mut response = Exchange::clone(exchange).response;
+                    self.check_response_headers(request.uri(), &mut response)
+                        .map(|()| response)
+                }
+                _ => self
+                    .new_bytes_decoder_from_range(offset, length)
+                    .and_then(|mut decoder| {
+                        let response = decoder.read_response().and_then(|mut response| {
+                            decoder.check_response_headers(request.uri(), &mut response)?;
+                            Ok(response)
+                        });
+                        self.merge(&mut decoder);
+                        response
+                    }),
+            };
+            match response {
+                Ok(response) => {
+                    exchanges.push(Exchange { request, response });
+                    last_location = Some((offset, length));
+                }
+                Err(err) if self.is_lenient() => {
+                    self.report(Issue::MalformedResponse {
+                        url: request.uri().to_string(),
+                        message: err.to_string(),
+                    })?;
+                    last_location = None;
+                }
+                Err(err) => return Err(err.in_exchange(request.uri())),
+            }
+        }
+        Ok(exchanges)
+    }
+
+    fn read_response(&mut self) -> Result<Response> {
         ensure!(
-            self.read_array_len()? as usize == bundle::TOP_ARRAY_LEN,
-            "Invalid header"
+            self.read_array_len()? == 2,
+            self.malformed("Failed to decode response entry")
         );
+        let headers = self.de.bytes_range()?;
+        let headers_offset = self.de.base_offset() + headers.start as u64;
+        let mut nested = Decoder::with_base_offset(&self.de.buf()[headers], headers_offset)
+            .with_options(self.options);
+        nested.check_canonical_item()?;
+        let (status, headers) = nested.read_headers_cbor()?;
+        self.issues.append(&mut nested.issues);
+        let body = self.read_shared_bytes()?;
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+
+    /// Applies the header policy to the response for the given URL.
+    fn check_response_headers(&mut self, uri: &Uri, response: &mut Response) -> Result<()> {
+        for name in validation::forbidden_headers(response.headers()) {
+            match self.options.header_policy {
+                HeaderPolicy::Reject => self.report(Issue::ForbiddenHeader {
+                    url: uri.to_string(),
+                    name: name.to_string(),
+                })?,
+                HeaderPolicy::Strip => {
+                    response.headers_mut().remove(name);
+                }
+                HeaderPolicy::Allow => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a byte string as a slice of the underlying buffer, without copying.
+    fn read_shared_bytes(&mut self) -> Result<Bytes> {
+        if let Some(len) = self.de.peek_head()?.argument {
+            self.check_body_size(len)?;
+        }
+        let range = self.de.bytes_range()?;
+        Ok(self.de.get_ref().slice(range))
+    }
+}
+
+impl<T: AsRef<[u8]>> Decoder<T> {
+    fn read_metadata(&mut self) -> Result<Metadata> {
+        let Header {
+            version,
+            primary_url,
+            section_offsets,
+        } = self.read_header()?;
+        let sections = self.read_sections(&version, &section_offsets)?;
+        Ok(Metadata {
+            primary_url: primary_url.or(sections.primary_url),
+            requests: sections.requests,
+            manifest: sections.manifest,
+            signatures: sections.signatures,
+            critical: sections.critical,
+            custom_sections: sections.custom,
+            version,
+            section_offsets,
+        })
+    }
+
+    fn read_header(&mut self) -> Result<Header> {
+        let top_array_len = self.read_array_len()? as usize;
         self.read_magic_bytes()?;
         let version = self.read_version()?;
-        let primary_url = self.read_primary_url()?;
-        let section_offsets = self.read_section_offsets()?;
-        let (requests, manifest) = self.read_sections(&section_offsets)?;
-        Ok(Metadata {
+        let primary_url = match version {
+            Version::VersionB1 => {
+                ensure!(
+                    top_array_len == bundle::TOP_ARRAY_LEN_B1,
+                    Error::malformed(Some(self.de.base_offset()), "Invalid header")
+                );
+                Some(self.read_primary_url()?)
+            }
+            Version::VersionB2 | Version::Version1 => {
+                ensure!(
+                    top_array_len == bundle::TOP_ARRAY_LEN,
+                    Error::malformed(Some(self.de.base_offset()), "Invalid header")
+                );
+                None
+            }
+            Version::Unknown(_) => return Err(Error::UnsupportedVersion(version)),
+        };
+        Ok(Header {
             version,
             primary_url,
-            section_offsets,
-            requests,
-            manifest,
+            section_offsets: self.read_section_offsets()?,
         })
     }
 
     fn read_magic_bytes(&mut self) -> Result<()> {
         log::debug!("read_magic_bytes");
-        let magic: Vec<u8> = self.de.bytes().context("Invalid magic bytes")?;
-        ensure!(magic == bundle::HEADER_MAGIC_BYTES, "Header magic mismatch");
+        let magic = self.de.bytes().map_err(|_| Error::MagicMismatch)?;
+        ensure!(magic == bundle::HEADER_MAGIC_BYTES, Error::MagicMismatch);
         Ok(())
     }
 
     fn read_version(&mut self) -> Result<Version> {
         log::debug!("read_version");
-        let bytes: Vec<u8> = self.de.bytes().context("Invalid version format")?;
-        ensure!(
-            bytes.len() == bundle::VERSION_BYTES_LEN,
-            "Invalid version format"
-        );
-        let version: [u8; bundle::VERSION_BYTES_LEN] =
-            AsRef::<[u8]>::as_ref(&bytes).try_into().unwrap();
+        let offset = self.offset();
+        let version: [u8; bundle::VERSION_BYTES_LEN] = self
+            .de
+            .bytes()?
+            .try_into()
+            .map_err(|_| Error::malformed(Some(offset), "Invalid version format"))?;
         Ok(if &version == bundle::Version::Version1.bytes() {
             Version::Version1
         } else if &version == bundle::Version::VersionB1.bytes() {
             Version::VersionB1
+        } else if &version == bundle::Version::VersionB2.bytes() {
+            Version::VersionB2
         } else {
             Version::Unknown(version)
         })
@@ -137,93 +551,168 @@ impl<T: AsRef<[u8]>> Decoder<T> {
 
     fn read_primary_url(&mut self) -> Result<Uri> {
         log::debug!("read_primary_url");
-        let url: String = self
-            .de
-            .text()
-            .context("bundle: Failed to read primary_url string")?;
-        url.parse().context("Failed to parse primary_url")
+        self.read_url()
+    }
+
+    fn read_url(&mut self) -> Result<Uri> {
+        let url = self.text()?;
+        url.parse()
+            .map_err(|_| Error::InvalidUrl(format!("{} at offset {}", url, self.offset())))
     }
 
     fn read_section_offsets(&mut self) -> Result<Vec<SectionOffset>> {
-        let bytes = self
-            .de
-            .bytes()
-            .context("Failed to read sectionLength byte string")?;
+        let range = self.de.bytes_range()?;
         ensure!(
-            bytes.len() < 8_192,
-            format!("sectionLengthsLength is too long ({} bytes)", bytes.len())
+            range.len() < 8_192,
+            Error::InvalidSectionTable(format!(
+                "sectionLengthsLength is too long ({} bytes)",
+                range.len()
+            ))
         );
-        Decoder::new(bytes).read_section_offsets_cbor(self.position())
+        let section_lengths_offset = self.de.base_offset() + range.start as u64;
+        // The first section starts after the head of the sections array.
+        let sections_offset = self.position() + self.de.peek_head()?.len as u64;
+        let mut decoder = Decoder::with_base_offset(&self.de.buf()[range], section_lengths_offset)
+            .with_options(self.options);
+        decoder.check_canonical_item()?;
+        let section_offsets = decoder.read_section_offsets_cbor(sections_offset)?;
+        self.issues.append(&mut decoder.issues);
+        Ok(section_offsets)
     }
 
     fn read_array_len(&mut self) -> Result<u64> {
-        match self.de.array()? {
-            Len::Len(n) => Ok(n),
-            _ => bail!("bundle: bundle: Failed to decode sectionOffset array header"),
-        }
+        self.de.array()
     }
 
     fn position(&self) -> u64 {
-        self.de.as_ref().position()
+        self.de.position() as u64
+    }
+
+    /// Returns the current offset in the input.
+    fn offset(&self) -> u64 {
+        self.de.offset()
+    }
+
+    fn malformed(&self, message: impl Into<String>) -> Error {
+        self.de.malformed(message)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        Ok(self.de.bytes()?.to_vec())
+    }
+
+    fn text(&mut self) -> Result<String> {
+        Ok(self.de.text()?.to_string())
+    }
+
+    fn unsigned_integer(&mut self) -> Result<u64> {
+        self.de.unsigned_integer()
     }
 
     fn read_section_offsets_cbor(&mut self, mut offset: u64) -> Result<Vec<SectionOffset>> {
-        let n = self
-            .read_array_len()
-            .context("bundle: bundle: Failed to decode sectionOffset array header")?;
+        let n = self.read_array_len()?;
+        ensure!(
+            n % 2 == 0,
+            Error::InvalidSectionTable(format!("Odd number of items: {}", n))
+        );
         let section_num = n / 2;
-        offset += self.position();
         let mut seen_names = HashSet::new();
-        let mut section_offsets = Vec::with_capacity(section_num as usize);
+        let mut section_offsets = Vec::new();
         for _ in 0..section_num {
-            let name = self.de.text()?;
-            ensure!(!seen_names.contains(&name), "Duplicate section name");
+            let name = self.text()?;
+            ensure!(
+                !seen_names.contains(&name),
+                Error::InvalidSectionTable(format!("Duplicate section name: {}", name))
+            );
             seen_names.insert(name.clone());
-            let length = self.de.unsigned_integer()?;
+            let length = self.unsigned_integer()?;
             section_offsets.push(SectionOffset {
                 name,
                 offset,
                 length,
             });
-            offset += length;
+            offset = offset.checked_add(length).ok_or_else(|| {
+                Error::InvalidSectionTable(format!("Section length is too large: {}", length))
+            })?;
         }
-        ensure!(!section_offsets.is_empty(), "bundle: section is empty");
+        ensure!(
+            !section_offsets.is_empty(),
+            Error::InvalidSectionTable("No sections".to_string())
+        );
         ensure!(
             section_offsets.last().unwrap().name == "responses",
-            "bundle: Last section is not \"responses\""
+            Error::InvalidSectionTable("Last section is not \"responses\"".to_string())
         );
         Ok(section_offsets)
     }
 
     fn inner_buf(&self) -> &[u8] {
-        self.de.as_ref().get_ref().as_ref()
+        self.de.buf()
     }
 
-    fn new_decoder_from_range(&self, start: u64, end: u64) -> Decoder<&[u8]> {
-        // TODO: Check range, instead of panic
-        Decoder::new(&self.inner_buf()[start as usize..end as usize])
+    /// Returns the range of the buffer at `offset` with `length`, or an error if
+    /// it is out of the buffer.
+    fn checked_range(&self, offset: u64, length: u64) -> Result<std::ops::Range<usize>> {
+        let out_of_range = || Error::OutOfRange {
+            offset: self.de.base_offset().saturating_add(offset),
+            length,
+        };
+        let start = usize::try_from(offset).map_err(|_| out_of_range())?;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| start.checked_add(length))
+            .filter(|&end| end <= self.inner_buf().len())
+            .ok_or_else(out_of_range)?;
+        Ok(start..end)
+    }
+
+    fn new_decoder_from_range(&self, offset: u64, length: u64) -> Result<Decoder<&[u8]>> {
+        let range = self.checked_range(offset, length)?;
+        Ok(
+            Decoder::with_base_offset(&self.inner_buf()[range], self.de.base_offset() + offset)
+                .with_options(self.options),
+        )
+    }
+
+    /// Returns the number of bytes after the current position.
+    fn remaining(&self) -> u64 {
+        self.de.remaining() as u64
+    }
+
+    fn check_body_size(&self, size: u64) -> Result<()> {
+        ensure!(
+            size <= self.limits().max_body_size,
+            Error::LimitExceeded(format!(
+                "The body at offset {} is larger than {} bytes",
+                self.offset(),
+                self.limits().max_body_size
+            ))
+        );
+        Ok(())
     }
 
     fn read_sections(
         &mut self,
+        version: &Version,
         section_offsets: &[SectionOffset],
-    ) -> Result<(Vec<RequestEntry>, Option<Manifest>)> {
+    ) -> Result<Sections> {
         log::debug!("read_sections");
-        let n = self
-            .read_array_len()
-            .context("Failed to read section header")?;
+        let n = self.read_array_len()?;
         ensure!(
             n as usize == section_offsets.len(),
-            format!(
-                "bundle: Expected {} sections, got {} sections",
+            Error::InvalidSectionTable(format!(
+                "Expected {} sections, got {} sections",
                 section_offsets.len(),
                 n
-            )
+            ))
         );
 
         let responses_section_offset = section_offsets.last().unwrap().offset;
-        let mut requests = vec![];
-        let mut manifest: Option<Manifest> = None;
+        let known_section_names: &[&str] = match version {
+            Version::VersionB1 => &bundle::KNOWN_SECTION_NAMES_B1,
+            _ => &bundle::KNOWN_SECTION_NAMES,
+        };
+        let mut sections = Sections::default();
 
         for SectionOffset {
             name,
@@ -231,155 +720,1313 @@ impl<T: AsRef<[u8]>> Decoder<T> {
             length,
         } in section_offsets
         {
-            if !bundle::KNOWN_SECTION_NAMES.iter().any(|&n| n == name) {
-                log::warn!("Unknows section name: {}. Skipping", name);
+            if !known_section_names.iter().any(|&n| n == name) {
+                // Keep an unknown section as is so that the encoder can write
+                // it back.
+                let range = self.checked_range(*offset, *length)?;
+                sections
+                    .custom
+                    .push((name.clone(), self.inner_buf()[range].to_vec()));
                 continue;
             }
-            let mut section_decoder = self.new_decoder_from_range(*offset, offset + length);
+            if name == "responses" {
+                // Skip responses section becuase we read responses later.
+                continue;
+            }
+            let mut section_decoder = self.new_decoder_from_range(*offset, *length)?;
 
             // TODO: Support ignoredSections
-            match name.as_ref() {
+            let result = match name.as_ref() {
                 "index" => {
-                    requests = section_decoder.read_index(responses_section_offset)?;
-                }
-                "responses" => {
-                    // Skip responses section becuase we read responses later.
-                }
-                "manifest" => {
-                    manifest = Some(section_decoder.read_manifest()?);
-                }
-                "signatures" => {
-                    log::warn!("signatues section is not supported yet");
+                    sections.requests = match version {
+                        Version::VersionB1 => {
+                            section_decoder.read_index_b1(responses_section_offset)?
+                        }
+                        _ => section_decoder.read_index(responses_section_offset)?,
+                    };
+                    Ok(())
                 }
+                "manifest" => section_decoder
+                    .read_manifest()
+                    .map(|manifest| sections.manifest = Some(manifest)),
+                "primary" => section_decoder
+                    .read_primary_url()
+                    .map(|primary_url| sections.primary_url = Some(primary_url)),
+                "signatures" => section_decoder
+                    .read_signatures()
+                    .map(|signatures| sections.signatures = Some(signatures)),
+                "critical" => section_decoder
+                    .read_critical()
+                    .map(|critical| sections.critical = critical),
                 _ => {
                     log::warn!("Unknown section found: {}", name);
+                    continue;
                 }
+            };
+            let actual = section_decoder.position();
+            let mut issues = std::mem::take(&mut section_decoder.issues);
+            self.issues.append(&mut issues);
+            match result {
+                // An optional section can be skipped.
+                Err(err) if self.is_lenient() && name != "primary" => {
+                    self.report(Issue::MalformedSection {
+                        name: name.clone(),
+                        message: err.to_string(),
+                    })?;
+                    continue;
+                }
+                result => result?,
+            }
+            if actual != *length {
+                self.report(Issue::SectionLengthMismatch {
+                    name: name.clone(),
+                    declared: *length,
+                    actual,
+                })?;
             }
         }
-        Ok((requests, manifest))
+        // A parser must fail if it doesn't understand a critical section,
+        // regardless of the parse mode.
+        if let Some(name) = sections
+            .critical
+            .iter()
+            .find(|name| !known_section_names.contains(&name.as_str()))
+        {
+            return Err(Error::Nonconforming(Issue::UnknownCriticalSection {
+                name: name.clone(),
+            }));
+        }
+        Ok(sections)
     }
 
     fn read_manifest(&mut self) -> Result<Uri> {
-        Ok(self.de.text()?.parse()?)
+        self.read_url()
     }
 
-    fn read_index(&mut self, responses_section_offset: u64) -> Result<Vec<RequestEntry>> {
-        let index_map_len = match self.de.map()? {
-            Len::Len(n) => n,
-            Len::Indefinite => {
-                bail!("bundle: Failed to decode index section map header");
+    fn read_critical(&mut self) -> Result<Vec<String>> {
+        let mut critical = Vec::new();
+        for _ in 0..self.read_array_len()? {
+            critical.push(self.text()?);
+        }
+        Ok(critical)
+    }
+
+    /// Checks that the CBOR item at the current position is canonically
+    /// encoded. A malformed item is left to be reported by the parser.
+    fn check_canonical_item(&mut self) -> Result<()> {
+        let start = self.position() as usize;
+        if let Ok(Some(offset)) = cbor::non_canonical_offset(&self.inner_buf()[start..]) {
+            let offset = self.offset() + offset as u64;
+            self.report(Issue::NonCanonicalCbor { offset })?;
+        }
+        Ok(())
+    }
+
+    /// Reports a duplicate URL in the index section.
+    fn check_duplicate_url(&mut self, seen: &mut HashSet<String>, uri: &Uri) -> Result<()> {
+        if !seen.insert(bundle::url_key(uri)) {
+            self.report(Issue::DuplicateUrl {
+                url: uri.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn read_map_len(&mut self) -> Result<u64> {
+        self.de.map()
+    }
+
+    fn skip_value(&mut self) -> Result<()> {
+        self.de.skip()
+    }
+
+    fn read_integrity_block(&mut self) -> Result<Vec<IntegritySignature>> {
+        ensure!(
+            self.read_array_len()? as usize == integrity_block::INTEGRITY_BLOCK_ARRAY_LEN,
+            self.malformed("Invalid integrity block")
+        );
+        ensure!(
+            self.bytes()? == integrity_block::INTEGRITY_BLOCK_MAGIC_BYTES,
+            Error::MagicMismatch
+        );
+        let version = self.bytes()?;
+        ensure!(
+            version == integrity_block::INTEGRITY_BLOCK_VERSION_BYTES,
+            Error::Unsupported(format!("Integrity block version: {:?}", version))
+        );
+        let signature_stack_len = self.read_array_len()?;
+        ensure!(
+            signature_stack_len > 0,
+            self.malformed("The signature stack is empty")
+        );
+        let mut signature_stack = Vec::new();
+        for _ in 0..signature_stack_len {
+            ensure!(
+                self.read_array_len()? == 2,
+                self.malformed("Failed to decode integrity signature")
+            );
+            let attributes_start = self.position() as usize;
+            let mut public_key = None;
+            for _ in 0..self.read_map_len()? {
+                match self.text()?.as_str() {
+                    integrity_block::ED25519_PUBLIC_KEY => public_key = Some(self.bytes()?),
+                    _ => self.skip_value()?,
+                }
             }
-        };
+            let attributes = self.inner_buf()[attributes_start..self.position() as usize].to_vec();
+            let public_key = public_key
+                .and_then(|public_key| public_key.as_slice().try_into().ok())
+                .and_then(|public_key| ed25519_dalek::VerifyingKey::from_bytes(&public_key).ok())
+                .ok_or_else(|| self.malformed("Invalid ed25519PublicKey"))?;
+            let signature = ed25519_dalek::Signature::from_slice(&self.bytes()?)
+                .map_err(|_| self.malformed("Invalid signature"))?;
+            signature_stack.push(IntegritySignature {
+                public_key,
+                signature,
+                attributes,
+            });
+        }
+        Ok(signature_stack)
+    }
+
+    fn read_signatures(&mut self) -> Result<Signatures> {
+        ensure!(
+            self.read_array_len()? == 2,
+            self.malformed("Failed to decode signatures section")
+        );
+        let mut authorities = Vec::new();
+        for _ in 0..self.read_array_len()? {
+            authorities.push(self.read_authority()?);
+        }
+        let mut vouched_subsets = Vec::new();
+        for _ in 0..self.read_array_len()? {
+            vouched_subsets.push(self.read_vouched_subset()?);
+        }
+        Ok(Signatures {
+            authorities,
+            vouched_subsets,
+        })
+    }
+
+    /// Returns an error for a missing field of a map.
+    fn missing(&self, field: &str, map: &str) -> Error {
+        self.malformed(format!("No {} in {}", field, map))
+    }
+
+    fn read_authority(&mut self) -> Result<Authority> {
+        let (mut cert, mut ocsp, mut sct) = (None, None, None);
+        for _ in 0..self.read_map_len()? {
+            match self.text()?.as_str() {
+                "cert" => cert = Some(self.bytes()?),
+                "ocsp" => ocsp = Some(self.bytes()?),
+                "sct" => sct = Some(self.bytes()?),
+                _ => self.skip_value()?,
+            }
+        }
+        Ok(Authority {
+            cert: cert.ok_or_else(|| self.missing("cert", "authority"))?,
+            ocsp,
+            sct,
+        })
+    }
+
+    fn read_vouched_subset(&mut self) -> Result<VouchedSubset> {
+        let (mut authority, mut sig, mut signed) = (None, None, None);
+        for _ in 0..self.read_map_len()? {
+            match self.text()?.as_str() {
+                "authority" => authority = Some(self.unsigned_integer()?),
+                "sig" => sig = Some(self.bytes()?),
+                "signed" => signed = Some(self.bytes()?),
+                _ => self.skip_value()?,
+            }
+        }
+        Ok(VouchedSubset {
+            authority: authority.ok_or_else(|| self.missing("authority", "vouched subset"))?,
+            sig: sig.ok_or_else(|| self.missing("sig", "vouched subset"))?,
+            signed: signed.ok_or_else(|| self.missing("signed", "vouched subset"))?,
+        })
+    }
+
+    fn read_signed_subset(&mut self) -> Result<SignedSubset> {
+        let (mut validity_url, mut auth_sha256, mut date, mut expires, mut subset_hashes) =
+            (None, None, None, None, None);
+        for _ in 0..self.read_map_len()? {
+            match self.text()?.as_str() {
+                "validity-url" => validity_url = Some(self.read_url()?),
+                "auth-sha256" => auth_sha256 = Some(self.bytes()?),
+                "date" => date = Some(self.unsigned_integer()?),
+                "expires" => expires = Some(self.unsigned_integer()?),
+                "subset-hashes" => subset_hashes = Some(self.read_subset_hashes()?),
+                _ => self.skip_value()?,
+            }
+        }
+        Ok(SignedSubset {
+            validity_url: validity_url
+                .ok_or_else(|| self.missing("validity-url", "signed subset"))?,
+            auth_sha256: auth_sha256.ok_or_else(|| self.missing("auth-sha256", "signed subset"))?,
+            date: date.ok_or_else(|| self.missing("date", "signed subset"))?,
+            expires: expires.ok_or_else(|| self.missing("expires", "signed subset"))?,
+            subset_hashes: subset_hashes
+                .ok_or_else(|| self.missing("subset-hashes", "signed subset"))?,
+        })
+    }
+
+    fn read_subset_hashes(&mut self) -> Result<Vec<SubsetHash>> {
+        let mut subset_hashes = Vec::new();
+        for _ in 0..self.read_map_len()? {
+            let uri = self.read_url()?;
+            let value_array_len = self.read_array_len()?;
+            ensure!(
+                value_array_len % 2 == 1,
+                self.malformed("Failed to decode subset-hashes value array")
+            );
+            let variants_value = self.bytes()?;
+            let mut resource_integrities = Vec::new();
+            for _ in 0..value_array_len / 2 {
+                resource_integrities.push(ResourceIntegrity {
+                    header_sha256: self.bytes()?,
+                    payload_integrity_header: self.text()?,
+                });
+            }
+            subset_hashes.push(SubsetHash {
+                uri,
+                variants_value,
+                resource_integrities,
+            });
+        }
+        Ok(subset_hashes)
+    }
+
+    fn read_index_map_len(&mut self) -> Result<u64> {
+        let len = self.read_map_len()?;
+        self.check_exchanges_len(len)?;
+        Ok(len)
+    }
+
+    fn check_exchanges_len(&self, len: u64) -> Result<()> {
+        ensure!(
+            len <= self.limits().max_exchanges as u64,
+            Error::LimitExceeded(format!(
+                "The bundle has more than {} exchanges",
+                self.limits().max_exchanges
+            ))
+        );
+        Ok(())
+    }
+
+    fn response_location(
+        &self,
+        responses_section_offset: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<ResponseLocation> {
+        ResponseLocation::new(responses_section_offset, offset, length)
+            .ok_or(Error::OutOfRange { offset, length })
+    }
+
+    fn read_index_value_array_len(&mut self) -> Result<u64> {
+        match self.read_array_len()? {
+            0 => Err(self.malformed("The value array of the index section is empty")),
+            n => Ok(n),
+        }
+    }
+
+    fn read_index_b1(&mut self, responses_section_offset: u64) -> Result<Vec<RequestEntry>> {
+        let index_map_len = self.read_index_map_len()?;
         let mut requests = vec![];
+        let mut seen_uris = HashSet::new();
         for _ in 0..index_map_len {
-            let uri = self.de.text()?.parse::<Uri>()?;
-
-            let value_array_len = match self.de.array()? {
-                Len::Len(0) => {
-                    bail!("bundle: Failed to decode index section. value array is empty");
+            let uri = self.read_url()?;
+            self.check_duplicate_url(&mut seen_uris, &uri)?;
+            let value_array_len = self.read_index_value_array_len()?;
+            let variants_value = self.bytes()?;
+            let variants = if variants_value.is_empty() {
+                None
+            } else {
+                Some(
+                    std::str::from_utf8(&variants_value)
+                        .ok()
+                        .and_then(|variants_value| Variants::parse(variants_value).ok())
+                        .ok_or_else(|| self.malformed("Invalid variants value"))?,
+                )
+            };
+            let locations_len = match &variants {
+                Some(variants) => variants
+                    .keys_len()
+                    .ok_or_else(|| Error::Unsupported("Too many variants".to_string()))?,
+                None => 1,
+            };
+            ensure!(
+                Some(value_array_len) == locations_len.checked_mul(2).map(|n| n + 1),
+                self.malformed(format!(
+                    "The size of value array must be {}",
+                    locations_len.saturating_mul(2).saturating_add(1)
+                ))
+            );
+            // Several variant keys may share the same response, whose request
+            // has the headers of the first key.
+            let mut seen_locations = HashSet::new();
+            for i in 0..locations_len {
+                let offset = self.unsigned_integer()?;
+                let length = self.unsigned_integer()?;
+                if !seen_locations.insert((offset, length)) {
+                    continue;
                 }
-                Len::Len(n) => n,
-                Len::Indefinite => {
-                    bail!("bundle: Failed to decode index section value array headder");
+                self.check_exchanges_len(requests.len() as u64 + 1)?;
+                let mut request = Request::get(uri.clone()).body(())?;
+                if let Some(variants) = &variants {
+                    *request.headers_mut() = variants.request_headers(&variants.key_at(i))?;
                 }
-            };
+                requests.push(RequestEntry {
+                    request,
+                    response_location: self.response_location(
+                        responses_section_offset,
+                        offset,
+                        length,
+                    )?,
+                });
+            }
+        }
+        Ok(requests)
+    }
 
-            let variant_value = self.de.bytes()?;
-            ensure!(variant_value.is_empty(), "variant is not supported");
+    fn read_index(&mut self, responses_section_offset: u64) -> Result<Vec<RequestEntry>> {
+        let index_map_len = self.read_index_map_len()?;
+        let mut requests = vec![];
+        let mut seen_uris = HashSet::new();
+        for _ in 0..index_map_len {
+            let uri = self.read_url()?;
+            self.check_duplicate_url(&mut seen_uris, &uri)?;
             ensure!(
-                value_array_len == 3,
-                "bundle: The size of value array must be 3"
+                self.read_index_value_array_len()? == 2,
+                self.malformed("The size of value array must be 2")
             );
-            let offset = self.de.unsigned_integer()?;
-            let length = self.de.unsigned_integer()?;
+            let offset = self.unsigned_integer()?;
+            let length = self.unsigned_integer()?;
             requests.push(RequestEntry {
                 request: Request::get(uri).body(())?,
-                response_location: ResponseLocation::new(responses_section_offset, offset, length),
+                response_location: self.response_location(
+                    responses_section_offset,
+                    offset,
+                    length,
+                )?,
             });
         }
         Ok(requests)
     }
 
-    fn read_responses(&mut self, requests: Vec<RequestEntry>) -> Result<Vec<Exchange>> {
-        requests
-            .into_iter()
-            .map(
-                |RequestEntry {
-                     request,
-                     response_location: ResponseLocation { offset, length },
-                 }| {
-                    let response = self
-                        .new_decoder_from_range(offset, offset + length)
-                        .read_response()?;
-                    Ok(Exchange { request, response })
-                },
-            )
-            .collect()
-    }
-
-    fn read_response(&mut self) -> Result<Response> {
-        let responses_array_len = self
-            .read_array_len()
-            .context("bundle: Failed to decode responses section array headder")?;
+    fn read_headers_cbor(&mut self) -> Result<(StatusCode, HeaderMap)> {
+        let headers_map_len = self.read_map_len()?;
         ensure!(
-            responses_array_len == 2,
-            "bundle: Failed to decode response entry"
+            headers_map_len <= self.limits().max_headers as u64,
+            Error::LimitExceeded(format!(
+                "The response at offset {} has more than {} headers",
+                self.de.base_offset(),
+                self.limits().max_headers
+            ))
         );
-        log::debug!("read_response: headers byte 1");
-        let headers = self.de.bytes()?;
-        log::debug!("read_response: headers byte 2");
-        let mut nested = Decoder::new(headers);
-        let (status, headers) = nested.read_headers_cbor()?;
-        let body = self.de.bytes()?;
-        let mut response = Response::new(body);
-        *response.status_mut() = status;
-        *response.headers_mut() = headers;
-        Ok(response)
-    }
-
-    fn read_headers_cbor(&mut self) -> Result<(StatusCode, HeaderMap)> {
-        let headers_map_len = match self.de.map()? {
-            Len::Len(n) => n,
-            Len::Indefinite => {
-                bail!("bundle: Failed to decode responses headers map headder");
-            }
-        };
         let mut headers = HeaderMap::new();
         let mut status = None;
         for _ in 0..headers_map_len {
-            let name = String::from_utf8(self.de.bytes()?)?;
-            let value = String::from_utf8(self.de.bytes()?)?;
-            if name.starts_with(':') {
-                ensure!(name == ":status", "Unknown pseudo headers");
-                ensure!(status.is_none(), ":status is duplicated");
-                status = Some(value.parse()?);
+            let offset = self.offset();
+            let invalid = |message: String| {
+                Error::InvalidHeaders(format!("{} at offset {}", message, offset))
+            };
+            let name = self.bytes()?;
+            let value = self.bytes()?;
+            let display_name = String::from_utf8_lossy(&name);
+            if name.starts_with(b":") {
+                ensure!(
+                    name == b":status",
+                    invalid(format!("Unknown pseudo header: {}", display_name))
+                );
+                ensure!(
+                    status.is_none(),
+                    invalid(":status is duplicated".to_string())
+                );
+                status = Some(StatusCode::from_bytes(&value).map_err(|_| {
+                    invalid(format!(
+                        "Invalid :status: {}",
+                        String::from_utf8_lossy(&value)
+                    ))
+                })?);
                 continue;
             }
-            headers.insert(
-                HeaderName::from_lowercase(name.as_bytes())?,
-                HeaderValue::from_str(value.as_str())?,
+            // A header name which appears more than once, which a canonical
+            // map doesn't have, keeps every value.
+            headers.append(
+                HeaderName::from_lowercase(&name)
+                    .map_err(|_| invalid(format!("Invalid header name: {}", display_name)))?,
+                HeaderValue::from_bytes(&value)
+                    .map_err(|_| invalid(format!("Invalid header value of {}", display_name)))?,
             );
         }
-        ensure!(status.is_some(), "no :status header");
-        Ok((status.unwrap(), headers))
+        status
+            .ok_or_else(|| {
+                Error::InvalidHeaders(format!(
+                    "No :status header at offset {}",
+                    self.de.base_offset()
+                ))
+            })
+            .map(|status| (status, headers))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
-
-    // fn init_env_logger() {
-    //     let _ = env_logger::builder().is_test(true).try_init();
-    // }
-
-    // #[test]
-    // fn read_magic_test() -> Result<()> {
-    //     init_env_logger();
-    //     assert!(Decoder::new(bundle::HEADER_MAGIC_BYTES)
-    //         .read_magic_bytes()
-    //         .is_ok());
-    //     assert!(Decoder::new([]).read_magic_bytes().is_err());
-    //     Ok(())
-    // }
+    use super::*;
+    use crate::builder::Builder;
+
+    fn build_bundle(version: Version) -> Result<Bundle> {
+        let mut response = Response::new(b"hello".to_vec().into());
+        response
+            .headers_mut()
+            .insert("content-type", HeaderValue::from_static("text/plain"));
+        Bundle::builder()
+            .version(version)
+            .primary_url("https://example.com/index.html".parse()?)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/index.html").body(())?,
+                response,
+            })
+            .build()
+    }
+
+    fn assert_round_trip(bundle: &Bundle) -> Result<()> {
+        let decoded = Bundle::from_bytes(bundle.encode()?)?;
+        assert_eq!(decoded.version, bundle.version);
+        assert_eq!(decoded.primary_url, bundle.primary_url);
+        assert_eq!(decoded.manifest, bundle.manifest);
+        assert_eq!(decoded.exchanges.len(), bundle.exchanges.len());
+        for (a, b) in decoded.exchanges.iter().zip(bundle.exchanges.iter()) {
+            assert_eq!(a.request.uri(), b.request.uri());
+            assert_eq!(a.response.status(), b.response.status());
+            assert_eq!(a.response.headers(), b.response.headers());
+            assert_eq!(a.response.body(), b.response.body());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_magic_test() -> Result<()> {
+        assert!(Decoder::new(bundle::HEADER_MAGIC_BYTES)
+            .read_magic_bytes()
+            .is_err());
+        let mut se = cbor::Writer::new_vec();
+        se.write_bytes(bundle::HEADER_MAGIC_BYTES)?;
+        assert!(Decoder::new(se.into_inner()).read_magic_bytes().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_b1() -> Result<()> {
+        assert_round_trip(&build_bundle(Version::VersionB1)?)
+    }
+
+    #[test]
+    fn round_trip_version1() -> Result<()> {
+        assert_round_trip(&build_bundle(Version::Version1)?)?;
+
+        // The primary url is optional in version 1.
+        let mut bundle = build_bundle(Version::Version1)?;
+        bundle.primary_url = None;
+        assert_round_trip(&bundle)
+    }
+
+    #[test]
+    fn round_trip_b2() -> Result<()> {
+        let bundle = build_bundle(Version::VersionB2)?;
+        assert_eq!(&bundle.encode()?[11..15], b"b2\0\0");
+        assert_round_trip(&bundle)
+    }
+
+    #[test]
+    fn body_shares_buffer() -> Result<()> {
+        let bytes = Bytes::from(build_bundle(Version::Version1)?.encode()?);
+        let range = bytes.as_ptr_range();
+        let bundle = Bundle::from_bytes(bytes.clone())?;
+        let body = bundle.exchanges[0].response.body();
+        assert_eq!(body, &b"hello"[..]);
+        assert!(range.contains(&body.as_ptr()));
+
+        let cloned = bundle.clone();
+        assert_eq!(
+            cloned.exchanges[0].request.uri(),
+            bundle.exchanges[0].request.uri()
+        );
+        assert_eq!(cloned.exchanges[0].response.body().as_ptr(), body.as_ptr());
+        Ok(())
+    }
+
+    #[test]
+    fn open() -> Result<()> {
+        let bundle = build_bundle(Version::VersionB1)?;
+        let path = std::env::temp_dir().join(format!("webbundle-open-{}.wbn", std::process::id()));
+        std::fs::write(&path, bundle.encode()?)?;
+        let opened = Bundle::open(&path);
+        std::fs::remove_file(&path)?;
+        assert_eq!(opened?.exchanges[0].response.body(), &b"hello"[..]);
+        assert!(Bundle::open(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn version1_layout() -> Result<()> {
+        let bytes = build_bundle(Version::Version1)?.encode()?;
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.read_array_len()?, 5);
+        decoder.read_magic_bytes()?;
+        assert_eq!(decoder.read_version()?, Version::Version1);
+        let section_offsets = decoder.read_section_offsets()?;
+        let names = section_offsets
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["primary", "index", "responses"]);
+
+        let index = section_offsets.iter().find(|s| s.name == "index").unwrap();
+        let mut index_decoder = decoder.new_decoder_from_range(index.offset, index.length)?;
+        assert_eq!(index_decoder.read_index_map_len()?, 1);
+        assert_eq!(index_decoder.text()?, "https://example.com/index.html");
+        assert_eq!(index_decoder.read_index_value_array_len()?, 2);
+
+        // The last item is the length of the bundle.
+        let length: [u8; 8] = bytes[bytes.len() - 8..].try_into().unwrap();
+        assert_eq!(u64::from_be_bytes(length), bytes.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip_variants() -> Result<()> {
+        let exchange = |variant_key| {
+            crate::test_util::variant(
+                "https://example.com/",
+                "Accept-Language;en;fr;ja",
+                variant_key,
+            )
+        };
+        let bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(exchange("en")?)
+            .exchange(exchange("fr, ja")?)
+            .build()?;
+        let bytes = bundle.encode()?;
+        let decoded = Bundle::from_bytes(bytes.clone())?;
+        // "fr" and "ja" share the same response.
+        assert_eq!(decoded.exchanges.len(), 2);
+        // The request headers name the first variant key of each response.
+        let languages = decoded
+            .exchanges
+            .iter()
+            .map(|exchange| exchange.request.headers()["accept-language"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(languages, ["en", "fr"]);
+        assert_eq!(decoded.encode()?, bytes);
+
+        let request = |language: &'static str| -> Result<Request> {
+            Ok(Request::get("https://example.com/")
+                .header("accept-language", language)
+                .body(())?)
+        };
+        let body = |request: Request| {
+            decoded
+                .select(&request)
+                .map(|exchange| exchange.response.body().to_vec())
+        };
+        assert_eq!(body(request("en")?), Some(b"en".to_vec()));
+        assert_eq!(body(request("ja")?), Some(b"fr, ja".to_vec()));
+        assert_eq!(body(request("de")?), Some(b"en".to_vec()));
+
+        // A response for "ja" is missing.
+        let bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(exchange("en")?)
+            .exchange(exchange("fr")?)
+            .build()?;
+        assert!(bundle.encode().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn request_headers() -> Result<()> {
+        // Without a Variant-Key header, the request headers name the variant key.
+        let exchange = |language: &'static str| -> Result<Exchange> {
+            let mut response = Response::new(language.as_bytes().to_vec().into());
+            response.headers_mut().insert(
+                "variants",
+                HeaderValue::from_static("Accept-Language;en;fr"),
+            );
+            Ok(Exchange {
+                request: Request::get("https://example.com/")
+                    .header("accept-language", language)
+                    .body(())?,
+                response,
+            })
+        };
+        let bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(exchange("fr")?)
+            .exchange(exchange("en")?)
+            .build()?;
+        let decoded = Bundle::from_bytes(bundle.encode()?)?;
+        for exchange in &decoded.exchanges {
+            assert_eq!(
+                exchange.request.headers()["accept-language"].as_bytes(),
+                exchange.response.body()
+            );
+        }
+        for language in ["fr", "en"] {
+            let request = Request::get("https://example.com/")
+                .header("accept-language", language)
+                .body(())?;
+            assert_eq!(
+                decoded.select(&request).unwrap().response.body(),
+                language.as_bytes()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_is_not_supported_in_version1() -> Result<()> {
+        let mut bundle = build_bundle(Version::Version1)?;
+        bundle.manifest = Some("https://example.com/manifest.json".parse()?);
+        assert!(matches!(bundle.encode(), Err(Error::Unsupported(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_version() -> Result<()> {
+        let mut bytes = build_bundle(Version::Version1)?.encode()?;
+        // magic (1 + 1 + 8 bytes), then version (1 + 4 bytes).
+        bytes[11] = b'9';
+        assert!(matches!(
+            Bundle::from_bytes(bytes),
+            Err(Error::UnsupportedVersion(Version::Unknown(_)))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn error_kinds() -> Result<()> {
+        let bytes = build_bundle(Version::Version1)?.encode()?;
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[2] = 0;
+        assert!(matches!(
+            Bundle::from_bytes(wrong_magic),
+            Err(Error::MagicMismatch)
+        ));
+
+        let mut wrong_type = bytes;
+        // The top-level array becomes a map.
+        wrong_type[0] = 0xa5;
+        assert!(matches!(
+            Bundle::from_bytes(wrong_type),
+            Err(Error::MalformedCbor {
+                offset: Some(0),
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_or_corrupted() -> Result<()> {
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bytes = build_bundle(version)?.encode()?;
+            // A missing trailing length (1 + 8 bytes) is only an issue.
+            for len in 0..bytes.len() - 9 {
+                assert!(Bundle::from_bytes(bytes[..len].to_vec()).is_err());
+            }
+            // Must not panic.
+            for i in 0..bytes.len() {
+                for value in [0x00, 0x1b, 0x3b, 0x5b, 0x7b, 0x9b, 0xbb, 0xff] {
+                    let mut corrupted = bytes.clone();
+                    corrupted[i] = value;
+                    let _ = Bundle::from_bytes(corrupted);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_range() -> Result<()> {
+        // A byte string which claims to be 2^64 - 1 bytes long.
+        let mut decoder = Decoder::new(vec![0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert!(matches!(decoder.bytes(), Err(Error::OutOfRange { .. })));
+
+        let decoder = Decoder::new(vec![0; 10]);
+        assert!(decoder.new_decoder_from_range(5, 6).is_err());
+        assert!(decoder.new_decoder_from_range(u64::MAX, 1).is_err());
+        assert!(decoder.new_decoder_from_range(5, 5).is_ok());
+
+        // Deeply nested arrays.
+        let mut nested = vec![0x81; 100];
+        nested.push(0);
+        assert!(Decoder::new(nested).skip_value().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let bytes = build_bundle(Version::Version1)?.encode()?;
+        assert!(Bundle::from_bytes_with_limits(bytes.clone(), &Limits::default()).is_ok());
+        for limits in [
+            Limits::default().max_exchanges(0),
+            // `:status` and `content-type`.
+            Limits::default().max_headers(1),
+            Limits::default().max_body_size(4),
+        ] {
+            assert!(matches!(
+                Bundle::from_bytes_with_limits(bytes.clone(), &limits),
+                Err(Error::LimitExceeded(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bytes = build_bundle(version)?.encode()?;
+            assert_eq!(Bundle::validate(bytes)?.issues(), []);
+        }
+        let bytes = build_bundle(Version::VersionB2)?
+            .encode_signed(&[ed25519_dalek::SigningKey::from_bytes(&[1; 32])])?;
+        assert!(Bundle::validate(bytes)?.is_valid());
+
+        let bytes = build_bundle(Version::Version1)?.encode()?;
+        let len = bytes.len() as u64;
+
+        let mut wrong_length = bytes.clone();
+        *wrong_length.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            Bundle::validate(wrong_length)?.issues(),
+            [Issue::LengthMismatch {
+                declared: len ^ 1,
+                actual: len
+            }]
+        );
+
+        let no_length = bytes[..bytes.len() - 9].to_vec();
+        assert_eq!(
+            Bundle::validate(no_length)?.issues(),
+            [Issue::InvalidLengthField { offset: len - 9 }]
+        );
+
+        let mut trailing = bytes;
+        trailing.extend_from_slice(b"ab");
+        assert_eq!(
+            Bundle::validate(trailing)?.issues(),
+            [
+                Issue::LengthMismatch {
+                    declared: len,
+                    actual: len + 2
+                },
+                Issue::TrailingBytes {
+                    offset: len,
+                    length: 2
+                }
+            ]
+        );
+        Ok(())
+    }
+
+    /// Encodes a version 1 bundle which has the given sections.
+    fn encode_sections(sections: &[(&str, Vec<u8>)]) -> Result<Vec<u8>> {
+        let mut section_lengths = cbor::Writer::new_vec();
+        section_lengths.write_array(sections.len() as u64 * 2)?;
+        for (name, value) in sections {
+            section_lengths.write_text(name)?;
+            section_lengths.write_unsigned_integer(value.len() as u64)?;
+        }
+        let mut se = cbor::Writer::new_vec();
+        se.write_array(bundle::TOP_ARRAY_LEN as u64)?;
+        se.write_bytes(bundle::HEADER_MAGIC_BYTES)?;
+        se.write_bytes(Version::Version1.bytes())?;
+        se.write_bytes(section_lengths.into_inner())?;
+        se.write_array(sections.len() as u64)?;
+        for (_, value) in sections {
+            se.write_raw_bytes(value)?;
+        }
+        let mut bytes = se.into_inner();
+        let len = bytes.len() as u64 + 9;
+        bytes.push(0x48);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        Ok(bytes)
+    }
+
+    fn cbor(write: impl FnOnce(&mut cbor::Writer<Vec<u8>>) -> Result<()>) -> Result<Vec<u8>> {
+        let mut se = cbor::Writer::new_vec();
+        write(&mut se)?;
+        Ok(se.into_inner())
+    }
+
+    #[test]
+    fn many_sections() -> Result<()> {
+        // The array of section lengths has a longer header than the sections array.
+        let mut sections = vec![(
+            "primary",
+            cbor(|se| se.write_text("https://example.com/").map(|_| ()))?,
+        )];
+        for name in ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"] {
+            sections.push((name, cbor(|se| se.write_unsigned_integer(0).map(|_| ()))?));
+        }
+        sections.push(("responses", cbor(|se| se.write_array(0).map(|_| ()))?));
+        assert_eq!(sections.len(), 12);
+        let bytes = encode_sections(&sections)?;
+        assert_eq!(
+            Bundle::from_bytes(bytes.clone())?.primary_url(),
+            &Some("https://example.com/".parse()?)
+        );
+        assert!(Bundle::validate(bytes)?.is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_sections() -> Result<()> {
+        let primary = cbor(|se| se.write_text("https://example.com/").map(|_| ()))?;
+        let headers = cbor(|se| {
+            se.write_map(1)?;
+            se.write_bytes(b":status")?;
+            se.write_bytes(b"200")?;
+            Ok(())
+        })?;
+        let response = cbor(|se| {
+            se.write_array(2)?;
+            se.write_bytes(&headers)?;
+            se.write_bytes(b"")?;
+            Ok(())
+        })?;
+        let responses = |count: u64, padding: usize| -> Result<Vec<u8>> {
+            let mut bytes = cbor(|se| se.write_array(count).map(|_| ()))?;
+            for _ in 0..count {
+                bytes.extend_from_slice(&response);
+            }
+            bytes.resize(bytes.len() + padding, 0);
+            Ok(bytes)
+        };
+        assert_eq!(response.len(), 16);
+
+        let index = |offset: u64, length: u64| {
+            cbor(|se| {
+                se.write_map(1)?;
+                se.write_text("https://example.com/")?;
+                se.write_array(2)?;
+                se.write_unsigned_integer(offset)?;
+                se.write_unsigned_integer(length)?;
+                Ok(())
+            })
+        };
+        // The first response is at offset 1, after the array header.
+        let valid = encode_sections(&[
+            ("primary", primary.clone()),
+            ("index", index(1, 16)?),
+            ("responses", responses(1, 0)?),
+        ])?;
+        assert!(Bundle::validate(valid)?.is_valid());
+
+        // A padded primary section.
+        let mut padded_primary = primary.clone();
+        padded_primary.push(0);
+        let report = Bundle::validate(encode_sections(&[
+            ("primary", padded_primary),
+            ("index", index(1, 16)?),
+            ("responses", responses(1, 0)?),
+        ])?)?;
+        assert!(matches!(
+            report.issues(),
+            [Issue::SectionLengthMismatch { name, declared: 22, actual: 21 }] if name == "primary"
+        ));
+
+        // An extra response, and a padded responses section.
+        let report = Bundle::validate(encode_sections(&[
+            ("primary", primary.clone()),
+            ("index", index(1, 16)?),
+            ("responses", responses(2, 1)?),
+        ])?)?;
+        assert!(matches!(
+            report.issues(),
+            [
+                Issue::SectionLengthMismatch { name, declared: 34, actual: 33 },
+                Issue::ResponsesCountMismatch { responses: 2, index: 1 },
+            ] if name == "responses"
+        ));
+
+        // The index entry is longer than the response.
+        let report = Bundle::validate(encode_sections(&[
+            ("primary", primary),
+            ("index", index(1, 17)?),
+            ("responses", responses(1, 1)?),
+        ])?)?;
+        assert!(matches!(
+            report.issues(),
+            [
+                Issue::SectionLengthMismatch { .. },
+                Issue::DanglingIndexEntry { length: 17, .. },
+            ]
+        ));
+        Ok(())
+    }
+
+    fn strict() -> DecoderOptions {
+        DecoderOptions::default().mode(ParseMode::Strict)
+    }
+
+    #[test]
+    fn strict_mode() -> Result<()> {
+        // Encoded bundles conform to the spec.
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bytes = build_bundle(version)?.encode()?;
+            let (_, report) = Bundle::from_bytes_with_options(bytes, &strict())?;
+            assert!(report.is_valid());
+        }
+
+        let mut bytes = build_bundle(Version::Version1)?.encode()?;
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(Bundle::from_bytes(bytes.clone()).is_ok());
+        assert!(matches!(
+            Bundle::from_bytes_with_options(bytes, &strict()),
+            Err(Error::Nonconforming(Issue::LengthMismatch { .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn non_canonical() -> Result<()> {
+        let primary = cbor(|se| se.write_text("https://example.com/").map(|_| ()))?;
+        let responses = cbor(|se| se.write_array(0).map(|_| ()))?;
+        // Zero, which is encoded in two bytes.
+        let bytes = encode_sections(&[
+            ("primary", primary.clone()),
+            ("a", vec![0x18, 0x00]),
+            ("responses", responses.clone()),
+        ])?;
+        assert!(matches!(
+            Bundle::validate(bytes.clone())?.issues(),
+            [Issue::NonCanonicalCbor { .. }]
+        ));
+        assert!(matches!(
+            Bundle::from_bytes_with_options(bytes, &strict()),
+            Err(Error::Nonconforming(Issue::NonCanonicalCbor { .. }))
+        ));
+
+        // Unsorted map keys.
+        let bytes = encode_sections(&[
+            ("primary", primary),
+            ("a", vec![0xa2, 0x02, 0x00, 0x01, 0x00]),
+            ("responses", responses),
+        ])?;
+        assert!(matches!(
+            Bundle::validate(bytes)?.issues(),
+            [Issue::NonCanonicalCbor { .. }]
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_urls() -> Result<()> {
+        let index = cbor(|se| {
+            se.write_map(2)?;
+            for _ in 0..2 {
+                se.write_text("https://example.com/")?;
+                se.write_array(2)?;
+                se.write_unsigned_integer(1)?;
+                se.write_unsigned_integer(16)?;
+            }
+            Ok(())
+        })?;
+        let headers = cbor(|se| {
+            se.write_map(1)?;
+            se.write_bytes(b":status")?;
+            se.write_bytes(b"200")?;
+            Ok(())
+        })?;
+        let responses = cbor(|se| {
+            se.write_array(1)?;
+            se.write_array(2)?;
+            se.write_bytes(&headers)?;
+            se.write_bytes(b"")?;
+            Ok(())
+        })?;
+        let bytes = encode_sections(&[("index", index), ("responses", responses)])?;
+        let report = Bundle::validate(bytes)?;
+        assert!(report.issues().contains(&Issue::DuplicateUrl {
+            url: "https://example.com/".to_string()
+        }));
+        Ok(())
+    }
+
+    #[test]
+    fn headers() -> Result<()> {
+        let mut response = Response::new(Bytes::new());
+        let headers = response.headers_mut();
+        headers.append("link", HeaderValue::from_static("<a.css>"));
+        headers.append("link", HeaderValue::from_static("<b.css>"));
+        headers.insert("x-opaque", HeaderValue::from_bytes(b"caf\xe9")?);
+        let bundle = Bundle::builder()
+            .version(Version::Version1)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/").body(())?,
+                response,
+            })
+            .build()?;
+        let bytes = bundle.encode()?;
+        assert!(Bundle::validate(bytes.clone())?.is_valid());
+        let bundle = Bundle::from_bytes(bytes)?;
+        let headers = bundle.exchanges()[0].response.headers();
+        // The values are combined into one.
+        assert_eq!(headers["link"], "<a.css>, <b.css>");
+        assert_eq!(headers["x-opaque"].as_bytes(), b"caf\xe9");
+
+        let encode = |headers: &[(&[u8], &[u8])]| -> Result<Vec<u8>> {
+            let headers = cbor(|se| {
+                se.write_map(headers.len() as u64)?;
+                for (name, value) in headers {
+                    se.write_bytes(name)?;
+                    se.write_bytes(value)?;
+                }
+                Ok(())
+            })?;
+            let response = cbor(|se| {
+                se.write_array(2)?;
+                se.write_bytes(&headers)?;
+                se.write_bytes(b"")?;
+                Ok(())
+            })?;
+            let index = cbor(|se| {
+                se.write_map(1)?;
+                se.write_text("https://example.com/")?;
+                se.write_array(2)?;
+                se.write_unsigned_integer(1)?;
+                se.write_unsigned_integer(response.len() as u64)?;
+                Ok(())
+            })?;
+            let mut responses = cbor(|se| se.write_array(1).map(|_| ()))?;
+            responses.extend_from_slice(&response);
+            encode_sections(&[("index", index), ("responses", responses)])
+        };
+
+        // A repeated header name keeps every value.
+        let bytes = encode(&[
+            (&b":status"[..], &b"200"[..]),
+            (b"link", b"<a.css>"),
+            (b"link", b"<b.css>"),
+        ])?;
+        let options = DecoderOptions::default().mode(ParseMode::Normal);
+        let (bundle, report) = Bundle::from_bytes_with_options(bytes, &options)?;
+        let links = bundle.exchanges()[0]
+            .response
+            .headers()
+            .get_all("link")
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(links, ["<a.css>", "<b.css>"]);
+        assert!(matches!(report.issues(), [Issue::NonCanonicalCbor { .. }]));
+
+        // The error tells the exchange.
+        let bytes = encode(&[(&b":status"[..], &b"200"[..]), (b"link", b"a\nb")])?;
+        let err = Bundle::from_bytes(bytes).unwrap_err();
+        assert!(matches!(err, Error::InvalidHeaders(_)));
+        assert!(err.to_string().contains("https://example.com/"), "{}", err);
+
+        let mut response = Response::new(Bytes::new());
+        response
+            .headers_mut()
+            .insert("variants", HeaderValue::from_bytes(b"\xe9")?);
+        let err = Bundle::builder()
+            .version(Version::Version1)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/").body(())?,
+                response,
+            })
+            .build()?
+            .encode()
+            .unwrap_err();
+        assert!(err.to_string().contains("https://example.com/"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn forbidden_headers() -> Result<()> {
+        let builder = || -> Result<Builder> {
+            let mut response = Response::new(Bytes::new());
+            let headers = response.headers_mut();
+            headers.insert("content-type", HeaderValue::from_static("text/html"));
+            headers.insert("set-cookie", HeaderValue::from_static("a=b"));
+            headers.insert("connection", HeaderValue::from_static("x-hop"));
+            headers.insert("x-hop", HeaderValue::from_static("1"));
+            Ok(Bundle::builder()
+                .version(Version::Version1)
+                .exchange(Exchange {
+                    request: Request::get("https://example.com/").body(())?,
+                    response,
+                }))
+        };
+        let names = |bundle: &Bundle| {
+            bundle.exchanges()[0]
+                .response
+                .headers()
+                .keys()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let err = builder()?.build().unwrap_err();
+        assert!(err.to_string().contains("https://example.com/"), "{}", err);
+        let bundle = builder()?.header_policy(HeaderPolicy::Strip).build()?;
+        assert_eq!(names(&bundle), ["content-type"]);
+
+        let bytes = builder()?
+            .header_policy(HeaderPolicy::Allow)
+            .build()?
+            .encode()?;
+        let mut issues = Bundle::validate(bytes.clone())?
+            .issues()
+            .iter()
+            .map(|issue| match issue {
+                Issue::ForbiddenHeader { url, name } => {
+                    assert_eq!(url, "https://example.com/");
+                    name.clone()
+                }
+                issue => panic!("{}", issue),
+            })
+            .collect::<Vec<_>>();
+        issues.sort();
+        assert_eq!(issues, ["connection", "set-cookie", "x-hop"]);
+        let strict = DecoderOptions::default().mode(ParseMode::Strict);
+        assert!(Bundle::from_bytes_with_options(bytes.clone(), &strict).is_err());
+        let strip = DecoderOptions::default().header_policy(HeaderPolicy::Strip);
+        let (bundle, report) = Bundle::from_bytes_with_options(bytes, &strip)?;
+        assert!(report.is_valid());
+        assert_eq!(names(&bundle), ["content-type"]);
+        Ok(())
+    }
+
+    #[test]
+    fn critical_section() -> Result<()> {
+        let critical = |name: &str| {
+            cbor(|se| {
+                se.write_array(1)?;
+                se.write_text(name)?;
+                Ok(())
+            })
+        };
+        let responses = cbor(|se| se.write_array(0).map(|_| ()))?;
+        let bytes = encode_sections(&[
+            ("critical", critical("foo")?),
+            ("foo", cbor(|se| se.write_unsigned_integer(0).map(|_| ()))?),
+            ("responses", responses.clone()),
+        ])?;
+        // An unknown critical section is an error even in the lenient mode.
+        for mode in [ParseMode::Strict, ParseMode::Normal, ParseMode::Lenient] {
+            assert!(matches!(
+                Bundle::from_bytes_with_options(bytes.clone(), &DecoderOptions::default().mode(mode)),
+                Err(Error::Nonconforming(Issue::UnknownCriticalSection { name })) if name == "foo"
+            ));
+        }
+
+        let bytes = encode_sections(&[
+            ("critical", critical("primary")?),
+            (
+                "primary",
+                cbor(|se| se.write_text("https://example.com/").map(|_| ()))?,
+            ),
+            ("responses", responses),
+        ])?;
+        let (bundle, _) = Bundle::from_bytes_with_options(bytes, &strict())?;
+        assert_eq!(bundle.critical_sections(), ["primary"]);
+
+        let bundle = Bundle::builder()
+            .version(Version::Version1)
+            .primary_url("https://example.com/".parse()?)
+            .critical_section("primary")
+            .critical_section("index")
+            .build()?;
+        let (decoded, report) = Bundle::from_bytes_with_options(bundle.encode()?, &strict())?;
+        assert!(report.is_valid());
+        assert_eq!(decoded.critical_sections(), ["primary", "index"]);
+
+        // The builder rejects a critical section which the bundle doesn't have.
+        assert!(Bundle::builder()
+            .version(Version::Version1)
+            .critical_section("primary")
+            .build()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn custom_sections() -> Result<()> {
+        let foo = cbor(|se| se.write_unsigned_integer(1).map(|_| ()))?;
+        let bar = cbor(|se| se.write_text("bar").map(|_| ()))?;
+        let bytes = encode_sections(&[
+            ("foo", foo.clone()),
+            ("index", cbor(|se| se.write_map(0).map(|_| ()))?),
+            ("bar", bar.clone()),
+            ("responses", cbor(|se| se.write_array(0).map(|_| ()))?),
+        ])?;
+        let bundle = Bundle::from_bytes(bytes.clone())?;
+        let custom_sections = [("foo".to_string(), foo), ("bar".to_string(), bar)];
+        assert_eq!(bundle.custom_sections(), custom_sections);
+
+        // Sections keep their order.
+        assert_eq!(bundle.encode()?, bytes);
+
+        let bundle = Bundle::builder()
+            .version(Version::Version1)
+            .custom_section("foo", custom_sections[0].1.clone())
+            .custom_section("bar", custom_sections[1].1.clone())
+            .build()?;
+        let bundle = Bundle::from_bytes(bundle.encode()?)?;
+        assert_eq!(bundle.custom_sections(), custom_sections);
+
+        // A custom section can't be a known section, and must be a CBOR item.
+        let builder = || Bundle::builder().version(Version::Version1);
+        assert!(builder().custom_section("index", vec![0]).build().is_err());
+        assert!(builder()
+            .custom_section("foo", vec![0])
+            .custom_section("foo", vec![0])
+            .build()
+            .is_err());
+        assert!(builder().custom_section("foo", vec![]).build().is_err());
+        assert!(builder().custom_section("foo", vec![0, 0]).build().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_mode() -> Result<()> {
+        let bundle = Bundle::builder()
+            .version(Version::Version1)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/a").body(())?,
+                response: Response::new(b"a".to_vec().into()),
+            })
+            .exchange(Exchange {
+                request: Request::get("https://example.com/b").body(())?,
+                response: Response::new(b"b".to_vec().into()),
+            })
+            .build()?;
+        let mut bytes = bundle.encode()?;
+        // Breaks the :status of the first response.
+        let status = bytes.windows(3).position(|w| w == b"200").unwrap();
+        bytes[status] = b'x';
+        assert!(Bundle::from_bytes(bytes.clone()).is_err());
+
+        let options = DecoderOptions::default().mode(ParseMode::Lenient);
+        let (bundle, report) = Bundle::from_bytes_with_options(bytes, &options)?;
+        assert_eq!(bundle.exchanges().len(), 1);
+        assert_eq!(bundle.exchanges()[0].request.uri(), "https://example.com/b");
+        assert!(matches!(
+            report.issues(),
+            [Issue::MalformedResponse { url, .. }] if url == "https://example.com/a"
+        ));
+        Ok(())
+    }
 }
@@ -12,72 +12,49 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::bundle::{self, Bundle, Exchange, Response, Uri};
+use crate::bundle::{self, Bundle, Exchange, Response, Uri, Version};
+use crate::cbor::Writer;
+use crate::integrity_block::{self, IntegritySignature};
+use crate::options::EncoderOptions;
 use crate::prelude::*;
-use cbor_event::Len;
-use std::io::Write;
-
-use cbor_event::se::Serializer;
-
-struct CountWrite<W> {
-    count: usize,
-    inner: W,
-}
-
-impl<W> CountWrite<W> {
-    fn new(inner: W) -> Self {
-        CountWrite { count: 0, inner }
-    }
-}
-
-impl<W: Write> Write for CountWrite<W> {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        match self.inner.write(buf) {
-            Ok(n) => {
-                self.count += n;
-                Ok(n)
-            }
-            Err(e) => Err(e),
-        }
-    }
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.inner.flush()
-    }
-}
-
-pub(crate) fn encode<W: Write + Sized>(bundle: &Bundle, write: W) -> Result<()> {
-    Encoder::new(CountWrite::new(write)).encode(bundle)?;
+use crate::signatures::{Authority, Signatures, SignedSubset, VouchedSubset};
+use crate::variants::{self, VariantKey, Variants};
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+pub(crate) fn encode<W: Write + Sized>(
+    bundle: &Bundle,
+    write: W,
+    options: &EncoderOptions,
+) -> Result<()> {
+    let (responses, response_locations, responses_count) =
+        encode_responses(&bundle.exchanges, options)?;
+    encode_with_encoded_responses(
+        write,
+        bundle,
+        response_locations,
+        responses_count,
+        &responses[..],
+        responses.len(),
+    )?;
     Ok(())
 }
 
-pub(crate) fn encode_to_vec(bundle: &Bundle) -> Result<Vec<u8>> {
+pub(crate) fn encode_to_vec(bundle: &Bundle, options: &EncoderOptions) -> Result<Vec<u8>> {
     let mut write = Vec::new();
-    encode(bundle, &mut write)?;
+    encode(bundle, &mut write, options)?;
     Ok(write)
 }
 
 struct Encoder<W: Write> {
-    se: Serializer<W>,
-}
-
-trait Count {
-    fn count(&self) -> usize;
-}
-
-impl<W: Write> Count for Serializer<CountWrite<W>> {
-    fn count(&self) -> usize {
-        // Use unsafe because Serializer.0 is private.
-        // TODO: Avoid to use unsafe.
-        let se_ptr: *const Serializer<CountWrite<W>> = self;
-        let count_write_ptr = se_ptr.cast::<CountWrite<W>>();
-        unsafe { (*count_write_ptr).count }
-    }
+    se: Writer<W>,
 }
 
 impl<W: Write> Encoder<W> {
     fn new(write: W) -> Self {
         Encoder {
-            se: Serializer::new(write),
+            se: Writer::new(write),
         }
     }
 
@@ -92,172 +69,752 @@ impl<W: Write> Encoder<W> {
     }
 
     fn write_primary_url(&mut self, primary_url: &Uri) -> Result<()> {
-        self.se.write_text(&primary_url.to_string())?;
+        self.se.write_text(primary_url.to_string())?;
         Ok(())
     }
 }
 
-impl<W: Write + Sized> Encoder<CountWrite<W>> {
-    fn encode(&mut self, bundle: &Bundle) -> Result<()> {
-        self.se
-            .write_array(Len::Len(bundle::TOP_ARRAY_LEN as u64))?;
-        self.write_magic()?;
-        self.write_version(&bundle.version)?;
-        self.write_primary_url(&bundle.primary_url)?;
-
-        let sections = encode_sections(bundle)?;
+impl<W: Write + Sized> Encoder<W> {
+    /// Encodes the given bundle, except for its exchanges. The responses
+    /// section is written by `write_responses`.
+    fn encode_with_responses(
+        &mut self,
+        bundle: &Bundle,
+        response_locations: &[ResponseLocation],
+        responses_length: usize,
+        write_responses: impl FnOnce(&mut Writer<W>) -> Result<()>,
+    ) -> Result<()> {
+        match bundle.version {
+            Version::VersionB1 => {
+                self.se.write_array(bundle::TOP_ARRAY_LEN_B1 as u64)?;
+                self.write_magic()?;
+                self.write_version(&bundle.version)?;
+                self.write_primary_url(bundle.primary_url.as_ref().ok_or_else(|| {
+                    Error::InvalidBundle("primary_url is required in version b1".to_string())
+                })?)?;
+            }
+            Version::VersionB2 | Version::Version1 => {
+                self.se.write_array(bundle::TOP_ARRAY_LEN as u64)?;
+                self.write_magic()?;
+                self.write_version(&bundle.version)?;
+            }
+            Version::Unknown(_) => return Err(Error::UnsupportedVersion(bundle.version.clone())),
+        }
 
-        let section_length_cbor = encode_section_lengths(&sections)?;
+        let sections = encode_sections(bundle, response_locations)?;
+        let section_length_cbor = encode_section_lengths(&sections, responses_length)?;
         self.se.write_bytes(section_length_cbor)?;
 
-        self.se.write_array(Len::Len(sections.len() as u64))?;
+        // The responses section is the last one.
+        self.se.write_array(sections.len() as u64 + 1)?;
         for section in sections {
             self.se.write_raw_bytes(&section.bytes)?;
         }
+        write_responses(&mut self.se)?;
 
-        // Write the length of bytes
-        self.se.write_unsigned_integer(self.se.count() as u64 + 8)?; // 8 is the length of u64.
+        // Write the length of bytes as a big-endian byte string.
+        // 9 is the length of the byte string header (1 byte) and u64 (8 bytes).
+        let length = self.se.count() as u64 + 9;
+        self.se.write_bytes(length.to_be_bytes())?;
         Ok(())
     }
 }
 
-struct Section {
-    name: &'static str,
+/// Encodes a bundle whose responses are already encoded into `responses`,
+/// without the array header of the responses section. The exchanges of the
+/// given bundle are ignored.
+///
+/// `response_locations` are relative to the beginning of `responses`, which
+/// has `responses_count` responses. Several locations may share a response.
+pub(crate) fn encode_with_encoded_responses<W: Write>(
+    write: W,
+    bundle: &Bundle,
+    mut response_locations: Vec<ResponseLocation>,
+    responses_count: usize,
+    mut responses: impl Read,
+    responses_length: usize,
+) -> Result<W> {
+    let mut se = Writer::new_vec();
+    se.write_array(responses_count as u64)?;
+    let array_header = se.into_inner();
+    for location in &mut response_locations {
+        location.offset += array_header.len();
+    }
+
+    let mut encoder = Encoder::new(write);
+    encoder.encode_with_responses(
+        bundle,
+        &response_locations,
+        array_header.len() + responses_length,
+        |se| {
+            se.write_raw_bytes(&array_header)?;
+            let mut buf = vec![0; 64 * 1024];
+            let mut copied = 0;
+            loop {
+                let n = responses.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                se.write_raw_bytes(&buf[..n])?;
+                copied += n;
+            }
+            ensure!(
+                copied == responses_length,
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "The length of the responses changed while encoding",
+                ))
+            );
+            Ok(())
+        },
+    )?;
+    Ok(encoder.se.into_inner())
+}
+
+struct Section<'a> {
+    name: &'a str,
     bytes: Vec<u8>,
 }
 
-fn encode_sections(bundle: &Bundle) -> Result<Vec<Section>> {
+/// Encodes the sections other than the responses section.
+///
+/// The sections are in the order of the section table of the decoded bundle,
+/// if any, so that a canonical bundle is encoded into the same bytes. Otherwise,
+/// and for sections which the decoded bundle didn't have, the order is the
+/// known sections, custom sections, index and critical.
+fn encode_sections<'a>(
+    bundle: &'a Bundle,
+    response_locations: &[ResponseLocation],
+) -> Result<Vec<Section<'a>>> {
     let mut sections = Vec::new();
 
-    // manifest
-    if let Some(uri) = &bundle.manifest {
-        let bytes = encode_manifest_section(uri)?;
+    match bundle.version {
+        Version::VersionB1 => {
+            // manifest
+            if let Some(uri) = &bundle.manifest {
+                let bytes = encode_manifest_section(uri)?;
+                sections.push(Section {
+                    name: "manifest",
+                    bytes,
+                });
+            };
+            // signatures
+            if let Some(signatures) = &bundle.signatures {
+                sections.push(Section {
+                    name: "signatures",
+                    bytes: encode_signatures_section(signatures)?,
+                });
+            }
+        }
+        _ => {
+            ensure!(
+                bundle.manifest.is_none(),
+                Error::Unsupported(format!(
+                    "manifest section is not supported in version {:?}",
+                    bundle.version
+                ))
+            );
+            ensure!(
+                bundle.signatures.is_none(),
+                Error::Unsupported(format!(
+                    "signatures section is not supported in version {:?}",
+                    bundle.version
+                ))
+            );
+            // primary
+            if let Some(uri) = &bundle.primary_url {
+                sections.push(Section {
+                    name: "primary",
+                    bytes: encode_primary_section(uri)?,
+                });
+            }
+        }
+    }
+    for (name, bytes) in &bundle.custom_sections {
         sections.push(Section {
-            name: "manifest",
-            bytes,
+            name,
+            bytes: bytes.clone(),
         });
-    };
-
-    // responses
-    let (response_section_bytes, response_locations) = encode_response_section(&bundle.exchanges)?;
-
-    let response_section = Section {
-        name: "responses",
-        bytes: response_section_bytes,
-    };
-
-    // index from responses
-    let index_section = Section {
+    }
+    sections.push(Section {
         name: "index",
-        bytes: encode_index_section(&response_locations)?,
-    };
-
-    sections.push(index_section);
-    sections.push(response_section);
+        bytes: encode_index_section(&bundle.version, response_locations)?,
+    });
+    if !bundle.critical.is_empty() {
+        sections.push(Section {
+            name: "critical",
+            bytes: encode_critical_section(&bundle.critical)?,
+        });
+    }
+    // The sort is stable, so that unordered sections keep the default order.
+    sections.sort_by_key(|section| {
+        bundle
+            .section_order
+            .iter()
+            .position(|name| name == section.name)
+            .unwrap_or(usize::MAX)
+    });
     Ok(sections)
 }
 
+fn encode_primary_section(url: &Uri) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_text(url.to_string())?;
+    Ok(se.into_inner())
+}
+
 fn encode_manifest_section(url: &Uri) -> Result<Vec<u8>> {
-    let mut se = Serializer::new_vec();
+    let mut se = Writer::new_vec();
     se.write_text(url.to_string())?;
-    Ok(se.finalize())
+    Ok(se.into_inner())
 }
 
-struct ResponseLocation {
-    uri: Uri,
-    offset: usize,
-    length: usize,
+fn encode_critical_section(critical: &[String]) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_array(critical.len() as u64)?;
+    for name in critical {
+        se.write_text(name)?;
+    }
+    Ok(se.into_inner())
 }
 
-fn encode_response_section(exchanges: &[Exchange]) -> Result<(Vec<u8>, Vec<ResponseLocation>)> {
-    let mut se = Serializer::new(CountWrite::new(Vec::new()));
+/// Encodes a map of the given text keys and encoded values.
+fn encode_map(se: &mut Writer<Vec<u8>>, entries: Vec<(&str, Vec<u8>)>) -> Result<()> {
+    let entries = entries
+        .into_iter()
+        .map(|(key, value)| Ok((encode_text(key)?, value)))
+        .collect::<Result<Vec<_>>>()?;
+    se.write_map_entries(entries)?;
+    Ok(())
+}
 
-    se.write_array(Len::Len(exchanges.len() as u64))?;
+fn encode_text(text: &str) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_text(text)?;
+    Ok(se.into_inner())
+}
 
-    let mut response_locations = Vec::new();
+fn encode_bytes(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_bytes(bytes)?;
+    Ok(se.into_inner())
+}
 
-    for exchange in exchanges {
-        let offset = se.count();
+fn encode_unsigned_integer(n: u64) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_unsigned_integer(n)?;
+    Ok(se.into_inner())
+}
 
-        se.write_array(Len::Len(2))?;
-        se.write_bytes(&encode_headers(&exchange.response)?)?;
-        se.write_bytes(&exchange.response.body())?;
+fn encode_signatures_section(signatures: &Signatures) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_array(2)?;
+    se.write_array(signatures.authorities.len() as u64)?;
+    for authority in &signatures.authorities {
+        encode_authority(&mut se, authority)?;
+    }
+    se.write_array(signatures.vouched_subsets.len() as u64)?;
+    for vouched_subset in &signatures.vouched_subsets {
+        encode_vouched_subset(&mut se, vouched_subset)?;
+    }
+    Ok(se.into_inner())
+}
 
-        response_locations.push(ResponseLocation {
-            uri: exchange.request.uri().clone(),
-            offset,
-            length: se.count() - offset,
-        });
+fn encode_authority(se: &mut Writer<Vec<u8>>, authority: &Authority) -> Result<()> {
+    let mut entries = vec![("cert", encode_bytes(&authority.cert)?)];
+    if let Some(ocsp) = &authority.ocsp {
+        entries.push(("ocsp", encode_bytes(ocsp)?));
     }
+    if let Some(sct) = &authority.sct {
+        entries.push(("sct", encode_bytes(sct)?));
+    }
+    encode_map(se, entries)
+}
 
-    Ok((se.finalize().inner, response_locations))
+fn encode_vouched_subset(se: &mut Writer<Vec<u8>>, vouched_subset: &VouchedSubset) -> Result<()> {
+    encode_map(
+        se,
+        vec![
+            (
+                "authority",
+                encode_unsigned_integer(vouched_subset.authority)?,
+            ),
+            ("sig", encode_bytes(&vouched_subset.sig)?),
+            ("signed", encode_bytes(&vouched_subset.signed)?),
+        ],
+    )
 }
 
-fn encode_index_section(response_locations: &[ResponseLocation]) -> Result<Vec<u8>> {
-    // Map keys must be sorted.
-    // See [3.9. Canonical CBOR](https://tools.ietf.org/html/rfc7049#section-3.9)
-    let mut map = std::collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
+pub(crate) fn encode_signed_subset(signed_subset: &SignedSubset) -> Result<Vec<u8>> {
+    let mut subset_hashes = Vec::new();
+    for subset_hash in &signed_subset.subset_hashes {
+        let mut value = Writer::new_vec();
+        value.write_array(1 + 2 * subset_hash.resource_integrities.len() as u64)?;
+        value.write_bytes(&subset_hash.variants_value)?;
+        for resource_integrity in &subset_hash.resource_integrities {
+            value.write_bytes(&resource_integrity.header_sha256)?;
+            value.write_text(&resource_integrity.payload_integrity_header)?;
+        }
+        subset_hashes.push((
+            encode_text(&subset_hash.uri.to_string())?,
+            value.into_inner(),
+        ));
+    }
+    let mut subset_hashes_se = Writer::new_vec();
+    subset_hashes_se.write_map_entries(subset_hashes)?;
+
+    let mut se = Writer::new_vec();
+    encode_map(
+        &mut se,
+        vec![
+            (
+                "validity-url",
+                encode_text(&signed_subset.validity_url.to_string())?,
+            ),
+            ("auth-sha256", encode_bytes(&signed_subset.auth_sha256)?),
+            ("date", encode_unsigned_integer(signed_subset.date)?),
+            ("expires", encode_unsigned_integer(signed_subset.expires)?),
+            ("subset-hashes", subset_hashes_se.into_inner()),
+        ],
+    )?;
+    Ok(se.into_inner())
+}
 
-    for response_location in response_locations {
-        let mut key = Serializer::new_vec();
-        key.write_text(response_location.uri.to_string())?;
+pub(crate) fn encode_integrity_block(signature_stack: &[IntegritySignature]) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    se.write_array(integrity_block::INTEGRITY_BLOCK_ARRAY_LEN as u64)?;
+    se.write_bytes(integrity_block::INTEGRITY_BLOCK_MAGIC_BYTES)?;
+    se.write_bytes(integrity_block::INTEGRITY_BLOCK_VERSION_BYTES)?;
+    se.write_array(signature_stack.len() as u64)?;
+    for signature in signature_stack {
+        se.write_array(2)?;
+        se.write_raw_bytes(&signature.attributes)?;
+        se.write_bytes(signature.signature.to_bytes())?;
+    }
+    Ok(se.into_inner())
+}
 
-        let mut value = Serializer::new_vec();
-        value.write_array(Len::Len(3))?;
-        // TODO: Support variants.
-        value.write_bytes(b"")?;
-        value.write_unsigned_integer(response_location.offset as u64)?;
-        value.write_unsigned_integer(response_location.length as u64)?;
+pub(crate) fn encode_integrity_signature_attributes(
+    public_key: &ed25519_dalek::VerifyingKey,
+) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+    encode_map(
+        &mut se,
+        vec![(
+            integrity_block::ED25519_PUBLIC_KEY,
+            encode_bytes(public_key.as_bytes())?,
+        )],
+    )?;
+    Ok(se.into_inner())
+}
+
+pub(crate) struct ResponseLocation {
+    uri: Uri,
+    offset: usize,
+    pub(crate) length: usize,
+    variants_value: Option<String>,
+    variant_keys: Vec<VariantKey>,
+}
+
+/// Remembers the responses written so far, keyed by the SHA-256 digest of
+/// their encoding, so that an identical response is written only once.
+#[derive(Default)]
+pub(crate) struct SharedResponses {
+    offsets: HashMap<[u8; 32], usize>,
+}
 
-        map.insert(key.finalize(), value.finalize());
+impl SharedResponses {
+    /// Points `location` at a response written before if `response`, which is
+    /// the encoding at `location`, is identical to it, and returns true.
+    /// Otherwise remembers `location` and returns false.
+    ///
+    /// `is_written_at` returns true if `response` is written at the given
+    /// offset. It is called to compare the bytes when the digests match.
+    pub(crate) fn share(
+        &mut self,
+        response: &[u8],
+        location: &mut ResponseLocation,
+        is_written_at: impl FnOnce(usize) -> Result<bool>,
+    ) -> Result<bool> {
+        let digest = Sha256::digest(response).into();
+        match self.offsets.get(&digest) {
+            Some(&offset) if is_written_at(offset)? => {
+                location.offset = offset;
+                Ok(true)
+            }
+            // The digests collide, and the response is written again.
+            Some(_) => Ok(false),
+            None => {
+                self.offsets.insert(digest, location.offset);
+                Ok(false)
+            }
+        }
     }
+}
 
-    let mut se = Serializer::new_vec();
-    se.write_map(Len::Len(response_locations.len() as u64))?;
-    for (key, value) in map {
-        se.write_raw_bytes(&key)?;
-        se.write_raw_bytes(&value)?;
+/// Encodes the responses of the given exchanges, without the array header of
+/// the responses section. Returns the encoded responses, their locations and
+/// the number of the responses written.
+fn encode_responses(
+    exchanges: &[Exchange],
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, Vec<ResponseLocation>, usize)> {
+    let mut shared = options.dedup_responses.then(SharedResponses::default);
+    let mut bytes = Vec::new();
+    let mut response_locations = Vec::with_capacity(exchanges.len());
+    let mut responses_count = 0;
+    for exchange in exchanges {
+        let offset = bytes.len();
+        let mut location = encode_response(&mut bytes, exchange, offset)?;
+        let is_shared =
+            match &mut shared {
+                Some(shared) => {
+                    let (written, response) = bytes.split_at(offset);
+                    shared.share(response, &mut location, |shared_offset| {
+                        Ok(written.get(shared_offset..shared_offset + response.len())
+                            == Some(response))
+                    })?
+                }
+                None => false,
+            };
+        if is_shared {
+            bytes.truncate(offset);
+        } else {
+            responses_count += 1;
+        }
+        response_locations.push(location);
     }
-    Ok(se.finalize())
+    Ok((bytes, response_locations, responses_count))
 }
 
-fn encode_section_lengths(sections: &[Section]) -> Result<Vec<u8>> {
-    let mut se = Serializer::new_vec();
+/// Encodes the response of the given exchange, which is located at `offset`
+/// in the responses section.
+pub(crate) fn encode_response<W: Write>(
+    write: W,
+    exchange: &Exchange,
+    offset: usize,
+) -> Result<ResponseLocation> {
+    encode_response_inner(write, exchange, offset)
+        .map_err(|err| err.in_exchange(exchange.request.uri()))
+}
 
-    se.write_array(Len::Len((sections.len() * 2) as u64))?;
+fn encode_response_inner<W: Write>(
+    write: W,
+    exchange: &Exchange,
+    offset: usize,
+) -> Result<ResponseLocation> {
+    let mut se = Writer::new(write);
+    se.write_array(2)?
+        .write_bytes(&encode_headers(&exchange.response)?)?
+        .write_bytes(exchange.response.body())?;
+
+    let headers = exchange.response.headers();
+    let variant_keys = variants::exchange_variant_keys(exchange)?;
+    Ok(ResponseLocation {
+        uri: exchange.request.uri().clone(),
+        offset,
+        length: se.count(),
+        variants_value: headers
+            .get(variants::VARIANTS)
+            .map(|value| value.to_str().map(str::to_string))
+            .transpose()?,
+        variant_keys,
+    })
+}
+
+fn encode_index_section(
+    version: &Version,
+    response_locations: &[ResponseLocation],
+) -> Result<Vec<u8>> {
+    // Group the locations by URL. `write_map_entries` sorts the keys.
+    let mut map = HashMap::<String, Vec<&ResponseLocation>>::new();
+    for response_location in response_locations {
+        map.entry(bundle::url_key(&response_location.uri))
+            .or_default()
+            .push(response_location);
+    }
+
+    let mut entries = Vec::with_capacity(map.len());
+    for locations in map.into_values() {
+        let mut value = Writer::new_vec();
+        if *version == Version::VersionB1 {
+            encode_index_value_b1(&mut value, &locations)?;
+        } else {
+            ensure!(
+                locations.len() == 1,
+                Error::Unsupported(format!(
+                    "variants are not supported in version {:?}: {}",
+                    version, locations[0].uri
+                ))
+            );
+            value.write_array(2)?;
+            value.write_unsigned_integer(locations[0].offset as u64)?;
+            value.write_unsigned_integer(locations[0].length as u64)?;
+        }
+        // The URL is written as given, not as its lookup key.
+        entries.push((
+            encode_text(&locations[0].uri.to_string())?,
+            value.into_inner(),
+        ));
+    }
+    let mut se = Writer::new_vec();
+    se.write_map_entries(entries)?;
+    Ok(se.into_inner())
+}
+
+fn encode_index_value_b1(se: &mut Writer<Vec<u8>>, locations: &[&ResponseLocation]) -> Result<()> {
+    let uri = &locations[0].uri;
+    let variants_value = match &locations[0].variants_value {
+        Some(variants_value) => variants_value,
+        None => {
+            ensure!(
+                locations.len() == 1,
+                Error::InvalidBundle(format!(
+                    "Multiple responses without Variants header: {}",
+                    uri
+                ))
+            );
+            se.write_array(3)?;
+            se.write_bytes(b"")?;
+            se.write_unsigned_integer(locations[0].offset as u64)?;
+            se.write_unsigned_integer(locations[0].length as u64)?;
+            return Ok(());
+        }
+    };
+    ensure!(
+        locations
+            .iter()
+            .all(|location| location.variants_value.as_ref() == Some(variants_value)),
+        Error::InvalidBundle(format!("Variants header mismatch: {}", uri))
+    );
+
+    // One location for each possible variant key.
+    let keys = Variants::parse(variants_value)?.keys();
+    se.write_array(1 + 2 * keys.len() as u64)?;
+    se.write_bytes(variants_value.as_bytes())?;
+    for key in keys {
+        let location = locations
+            .iter()
+            .find(|location| location.variant_keys.contains(&key))
+            .ok_or_else(|| {
+                Error::InvalidBundle(format!("No response for variant key {:?}: {}", key, uri))
+            })?;
+        se.write_unsigned_integer(location.offset as u64)?;
+        se.write_unsigned_integer(location.length as u64)?;
+    }
+    Ok(())
+}
+
+fn encode_section_lengths(sections: &[Section], responses_length: usize) -> Result<Vec<u8>> {
+    let mut se = Writer::new_vec();
+
+    se.write_array((sections.len() as u64 + 1) * 2)?;
     for section in sections {
         se.write_text(section.name)?;
         se.write_unsigned_integer(section.bytes.len() as u64)?;
     }
-    Ok(se.finalize())
-}
-
-fn encode_headers(response: &Response) -> Result<Vec<u8>> {
-    // Map keys must be sorted.
-    // See [3.9. Canonical CBOR](https://tools.ietf.org/html/rfc7049#section-3.9)
-    let mut map = std::collections::BTreeMap::<Vec<u8>, Vec<u8>>::new();
-
-    // Write status
-    let mut key = Serializer::new_vec();
-    key.write_bytes(b":status")?;
-    let mut value = Serializer::new_vec();
-    value.write_bytes(response.status().as_u16().to_string().as_bytes())?;
-    map.insert(key.finalize(), value.finalize());
-
-    // Write headers
-    for (header_name, header_value) in response.headers() {
-        let mut key = Serializer::new_vec();
-        key.write_bytes(header_name.as_str().as_bytes())?;
-        let mut value = Serializer::new_vec();
-        value.write_bytes(header_value.to_str()?.as_bytes())?;
-        map.insert(key.finalize(), value.finalize());
+    se.write_text("responses")?;
+    se.write_unsigned_integer(responses_length as u64)?;
+    Ok(se.into_inner())
+}
+
+pub(crate) fn encode_headers(response: &Response) -> Result<Vec<u8>> {
+    let headers = response.headers();
+    let mut entries = vec![(
+        encode_bytes(b":status")?,
+        encode_bytes(response.status().as_str().as_bytes())?,
+    )];
+    for header_name in headers.keys() {
+        // The values of a header which appears more than once are combined
+        // into one, as a map can't have the same key twice.
+        let value = headers
+            .get_all(header_name)
+            .iter()
+            .map(http::HeaderValue::as_bytes)
+            .collect::<Vec<_>>()
+            .join(&b", "[..]);
+        entries.push((
+            encode_bytes(header_name.as_str().as_bytes())?,
+            encode_bytes(&value)?,
+        ));
+    }
+    let mut se = Writer::new_vec();
+    se.write_map_entries(entries)?;
+    Ok(se.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::Request;
+    use crate::decoder;
+    use crate::{BundleWriter, DecoderOptions};
+    use bytes::Bytes;
+
+    /// Exchanges whose bodies cross the boundaries of CBOR length encodings.
+    fn exchanges() -> Result<Vec<Exchange>> {
+        [0, 1, 23, 24, 255, 256, 65_535, 65_536, 1 << 20]
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                Ok(Exchange {
+                    request: Request::get(format!("https://example.com/{}", i)).body(())?,
+                    response: Response::new(vec![i as u8; size].into()),
+                })
+            })
+            .collect()
     }
 
-    let mut se = Serializer::new_vec();
-    se.write_map(Len::Len(map.len() as u64))?;
-    for (key, value) in map {
-        se.write_raw_bytes(&key)?;
-        se.write_raw_bytes(&value)?;
+    /// Asserts that the index section points at each response, and that the
+    /// responses are laid out back to back in the responses section.
+    fn assert_response_offsets(bytes: Vec<u8>) -> Result<()> {
+        let options = DecoderOptions::default();
+        let metadata = decoder::parse_metadata(&bytes, &options)?;
+        let responses_section = metadata.section_offsets.last().unwrap();
+        let mut array_header = Writer::new_vec();
+        array_header.write_array(metadata.requests.len() as u64)?;
+        let mut offset = responses_section.offset + array_header.into_inner().len() as u64;
+
+        let bytes = Bytes::from(bytes);
+        let mut requests = metadata.requests;
+        requests.sort_by_key(|request| request.response_location.offset);
+        let exchanges = exchanges()?;
+        assert_eq!(requests.len(), exchanges.len());
+        for (request, exchange) in requests.iter().zip(&exchanges) {
+            assert_eq!(request.request.uri(), exchange.request.uri());
+            let location = request.response_location;
+            assert_eq!(location.offset, offset);
+            let range = location.offset as usize..(location.offset + location.length) as usize;
+            let response = decoder::parse_response(
+                bytes.slice(range),
+                offset,
+                request.request.uri(),
+                &options,
+            )?;
+            assert_eq!(response.body(), exchange.response.body());
+            offset += location.length;
+        }
+        assert_eq!(offset, responses_section.offset + responses_section.length);
+        Ok(())
+    }
+
+    #[test]
+    fn response_offsets() -> Result<()> {
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bundle = exchanges()?
+                .into_iter()
+                .fold(Bundle::builder(), |builder, exchange| {
+                    builder.exchange(exchange)
+                })
+                .version(version.clone())
+                .primary_url("https://example.com/0".parse()?)
+                .build()?;
+            assert_response_offsets(bundle.encode()?)?;
+
+            let mut writer = BundleWriter::new(Vec::new(), version)?
+                .primary_url("https://example.com/0".parse()?);
+            for exchange in exchanges()? {
+                writer.add_exchange(exchange)?;
+            }
+            assert_response_offsets(writer.finish()?)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dedup_responses() -> Result<()> {
+        let exchanges = || -> Result<Vec<Exchange>> {
+            ["a", "b", "c/index.html"]
+                .iter()
+                .zip(["same", "other", "same"])
+                .map(|(path, body)| {
+                    Ok(Exchange {
+                        request: Request::get(format!("https://example.com/{}", path)).body(())?,
+                        response: Response::new(body.into()),
+                    })
+                })
+                .collect()
+        };
+        let options = EncoderOptions::default().dedup_responses(true);
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bundle = exchanges()?
+                .into_iter()
+                .fold(Bundle::builder(), |builder, exchange| {
+                    builder.exchange(exchange)
+                })
+                .version(version.clone())
+                .primary_url("https://example.com/a".parse()?)
+                .build()?;
+            let bytes = bundle.encode_with_options(&options)?;
+            assert!(bytes.len() < bundle.encode()?.len());
+
+            let mut writer = BundleWriter::new(Vec::new(), version)?
+                .primary_url("https://example.com/a".parse()?)
+                .dedup_responses(true);
+            for exchange in exchanges()? {
+                writer.add_exchange(exchange)?;
+            }
+            assert_eq!(writer.finish()?, bytes);
+
+            let metadata = decoder::parse_metadata(&bytes, &DecoderOptions::default())?;
+            let offsets = metadata
+                .requests
+                .iter()
+                .map(|entry| (entry.request.uri().path(), entry.response_location.offset))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(offsets["/a"], offsets["/c/index.html"]);
+            assert_ne!(offsets["/a"], offsets["/b"]);
+
+            let (decoded, report) =
+                Bundle::from_bytes_with_options(bytes.clone(), &DecoderOptions::default())?;
+            assert!(report.is_valid(), "{:?}", report);
+            // The decoded bundle keeps sharing the responses.
+            assert_eq!(decoded.encode()?, bytes);
+            assert_eq!(decoded.exchanges().len(), 3);
+            for exchange in exchanges()? {
+                let uri = exchange.request.uri();
+                assert_eq!(
+                    decoded.get(uri).unwrap().response.body(),
+                    exchange.response.body()
+                );
+            }
+        }
+
+        // A bundle which shares a response, and has another identical one
+        // which is not shared, isn't encoded into the same bytes.
+        let bundle = (0..3)
+            .map(|i| {
+                Ok(Exchange {
+                    request: Request::get(format!("https://example.com/{}", i)).body(())?,
+                    response: Response::new("same".into()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .fold(Bundle::builder(), |builder, exchange| {
+                builder.exchange(exchange)
+            })
+            .version(Version::VersionB2)
+            .build()?;
+        let (mut responses, mut locations, _) =
+            encode_responses(&bundle.exchanges, &EncoderOptions::default())?;
+        responses.truncate(locations[2].offset);
+        locations[2].offset = locations[1].offset;
+        locations[1].offset = locations[0].offset;
+        let bytes = encode_with_encoded_responses(
+            Vec::new(),
+            &bundle,
+            locations,
+            2,
+            &responses[..],
+            responses.len(),
+        )?;
+        let report = Bundle::validate(bytes)?;
+        assert!(
+            matches!(report.issues(), [crate::Issue::DuplicateResponse { .. }]),
+            "{:?}",
+            report
+        );
+        Ok(())
     }
-    Ok(se.finalize())
 }
@@ -0,0 +1,142 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bundle::Version;
+use crate::validation::Issue;
+
+/// The error type of this crate.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The magic bytes of a bundle or an integrity block don't match.
+    #[error("Magic bytes mismatch")]
+    MagicMismatch,
+    /// The version of a bundle or an integrity block is not supported.
+    #[error("Unsupported version: {0:?}")]
+    UnsupportedVersion(Version),
+    /// The input is not well-formed CBOR, or doesn't have the expected structure.
+    #[error("Malformed CBOR{}: {message}", at_offset(.offset))]
+    MalformedCbor {
+        /// The byte offset in the bundle where the error is detected, if known.
+        offset: Option<u64>,
+        message: String,
+    },
+    /// The section lengths or the sections don't match.
+    #[error("Invalid section table: {0}")]
+    InvalidSectionTable(String),
+    /// An offset or a length points outside of the input.
+    #[error("Out of range: offset {offset}, length {length}")]
+    OutOfRange { offset: u64, length: u64 },
+    /// The bundle has an [`Issue`], which is an error in
+    /// [`ParseMode::Strict`](crate::ParseMode::Strict).
+    #[error("Nonconforming bundle: {0}")]
+    Nonconforming(Issue),
+    /// The bundle exceeds one of the [`Limits`](crate::Limits).
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+    /// HTTP headers or a status code are invalid.
+    #[error("Invalid headers: {0}")]
+    InvalidHeaders(String),
+    /// A URL is invalid.
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    /// The contents of a bundle are inconsistent, such as an index entry
+    /// without a response.
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+    /// A feature is not supported, such as a section which the version of a
+    /// bundle can't represent.
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+    /// A web app manifest is invalid.
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+    /// Signing or verifying signatures failed.
+    #[error("Signature error: {0}")]
+    Signature(String),
+    /// An I/O error.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+fn at_offset(offset: &Option<u64>) -> String {
+    offset
+        .map(|offset| format!(" at offset {}", offset))
+        .unwrap_or_default()
+}
+
+impl Error {
+    pub(crate) fn malformed(offset: Option<u64>, message: impl Into<String>) -> Error {
+        Error::MalformedCbor {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// Adds the URL of the exchange to an error about its headers.
+    pub(crate) fn in_exchange(self, uri: &http::Uri) -> Error {
+        match self {
+            Error::InvalidHeaders(message) => {
+                Error::InvalidHeaders(format!("{} in the exchange for {}", message, uri))
+            }
+            err => err,
+        }
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Error {
+        if err.is::<http::uri::InvalidUri>() || err.is::<http::uri::InvalidUriParts>() {
+            Error::InvalidUrl(err.to_string())
+        } else {
+            Error::InvalidHeaders(err.to_string())
+        }
+    }
+}
+
+impl From<http::uri::InvalidUri> for Error {
+    fn from(err: http::uri::InvalidUri) -> Error {
+        Error::InvalidUrl(err.to_string())
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Error {
+        Error::InvalidUrl(err.to_string())
+    }
+}
+
+impl From<http::header::InvalidHeaderName> for Error {
+    fn from(err: http::header::InvalidHeaderName) -> Error {
+        Error::InvalidHeaders(err.to_string())
+    }
+}
+
+impl From<http::header::InvalidHeaderValue> for Error {
+    fn from(err: http::header::InvalidHeaderValue) -> Error {
+        Error::InvalidHeaders(err.to_string())
+    }
+}
+
+impl From<http::header::ToStrError> for Error {
+    fn from(err: http::header::ToStrError) -> Error {
+        Error::InvalidHeaders(err.to_string())
+    }
+}
+
+impl From<http::status::InvalidStatusCode> for Error {
+    fn from(err: http::status::InvalidStatusCode) -> Error {
+        Error::InvalidHeaders(err.to_string())
+    }
+}
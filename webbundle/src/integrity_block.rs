@@ -0,0 +1,248 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the integrity block of Signed Web Bundles.
+//!
+//! See [Integrity Block](https://github.com/WICG/webpackage/blob/main/explainers/integrity-signature.md).
+
+use crate::encoder;
+use crate::prelude::*;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use sha2::{Digest as _, Sha512};
+
+pub(crate) const INTEGRITY_BLOCK_MAGIC_BYTES: [u8; 8] =
+    [0xf0, 0x9f, 0x96, 0x8b, 0xf0, 0x9f, 0x93, 0xa6];
+pub(crate) const INTEGRITY_BLOCK_VERSION_BYTES: [u8; 4] = [0x31, 0x62, 0, 0];
+pub(crate) const INTEGRITY_BLOCK_ARRAY_LEN: usize = 3;
+pub(crate) const ED25519_PUBLIC_KEY: &str = "ed25519PublicKey";
+
+/// The suffix of a web bundle ID which is derived from an Ed25519 public key.
+const WEB_BUNDLE_ID_SUFFIX: [u8; 3] = [0x00, 0x01, 0x02];
+
+/// Represents an integrity block which precedes a signed web bundle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityBlock {
+    signature_stack: Vec<IntegritySignature>,
+    web_bundle_hash: Vec<u8>,
+}
+
+/// Represents a signature in the signature stack.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegritySignature {
+    pub(crate) public_key: VerifyingKey,
+    pub(crate) signature: Signature,
+    /// The CBOR-encoded signature attributes, which are a part of the signed data.
+    pub(crate) attributes: Vec<u8>,
+}
+
+impl IntegritySignature {
+    /// Gets the public key.
+    pub fn public_key(&self) -> &VerifyingKey {
+        &self.public_key
+    }
+
+    /// Gets the signature.
+    pub fn signature(&self) -> &Signature {
+        &self.signature
+    }
+}
+
+impl IntegrityBlock {
+    pub(crate) fn new(signature_stack: Vec<IntegritySignature>, web_bundle: &[u8]) -> Self {
+        IntegrityBlock {
+            signature_stack,
+            web_bundle_hash: Sha512::digest(web_bundle).to_vec(),
+        }
+    }
+
+    /// Gets the signature stack.
+    pub fn signature_stack(&self) -> &[IntegritySignature] {
+        &self.signature_stack
+    }
+
+    /// Gets the web bundle ID, which is derived from the public key of the
+    /// first signature.
+    pub fn web_bundle_id(&self) -> Result<String> {
+        let signature = self
+            .signature_stack
+            .first()
+            .ok_or_else(|| Error::Signature("The signature stack is empty".to_string()))?;
+        Ok(web_bundle_id(&signature.public_key))
+    }
+
+    /// Verifies every signature in the signature stack against the web bundle.
+    pub fn verify(&self) -> Result<()> {
+        ensure!(
+            !self.signature_stack.is_empty(),
+            Error::Signature("The signature stack is empty".to_string())
+        );
+        for signature in &self.signature_stack {
+            let payload = signature_payload(&self.web_bundle_hash, &signature.attributes)?;
+            signature
+                .public_key
+                .verify_strict(&payload, &signature.signature)
+                .map_err(|err| Error::Signature(format!("Verification failed: {}", err)))?;
+        }
+        Ok(())
+    }
+
+    /// Verifies the signature stack, and checks that the given public key is
+    /// one of the signers.
+    pub fn verify_with(&self, public_key: &VerifyingKey) -> Result<()> {
+        self.verify()?;
+        ensure!(
+            self.signature_stack
+                .iter()
+                .any(|signature| signature.public_key == *public_key),
+            Error::Signature("Not signed by the given public key".to_string())
+        );
+        Ok(())
+    }
+}
+
+/// Derives the web bundle ID from an Ed25519 public key.
+///
+/// The ID is the lowercase base32 encoding, without padding, of the public
+/// key followed by `[0x00, 0x01, 0x02]`.
+pub fn web_bundle_id(public_key: &VerifyingKey) -> String {
+    let mut bytes = public_key.as_bytes().to_vec();
+    bytes.extend_from_slice(&WEB_BUNDLE_ID_SUFFIX);
+    data_encoding::BASE32_NOPAD
+        .encode(&bytes)
+        .to_ascii_lowercase()
+}
+
+/// Returns true if the given bytes start with an integrity block.
+pub(crate) fn has_integrity_block(bytes: &[u8]) -> bool {
+    // An array header (1 byte), and a byte string header (1 byte) for the magic.
+    bytes.get(2..2 + INTEGRITY_BLOCK_MAGIC_BYTES.len()) == Some(&INTEGRITY_BLOCK_MAGIC_BYTES)
+}
+
+/// Returns the data which each signature signs.
+fn signature_payload(web_bundle_hash: &[u8], attributes: &[u8]) -> Result<Vec<u8>> {
+    let integrity_block = encoder::encode_integrity_block(&[])?;
+    let mut payload = Vec::new();
+    for item in &[web_bundle_hash, &integrity_block, attributes] {
+        payload.extend_from_slice(&(item.len() as u64).to_be_bytes());
+        payload.extend_from_slice(item);
+    }
+    Ok(payload)
+}
+
+/// Signs the given web bundle, returning the encoded integrity block.
+pub(crate) fn sign(web_bundle: &[u8], signing_keys: &[SigningKey]) -> Result<Vec<u8>> {
+    ensure!(
+        !signing_keys.is_empty(),
+        Error::Signature("No signing key".to_string())
+    );
+    let web_bundle_hash = Sha512::digest(web_bundle);
+    let signature_stack = signing_keys
+        .iter()
+        .map(|signing_key| {
+            let public_key = signing_key.verifying_key();
+            let attributes = encoder::encode_integrity_signature_attributes(&public_key)?;
+            let signature = signing_key.sign(&signature_payload(&web_bundle_hash, &attributes)?);
+            Ok(IntegritySignature {
+                public_key,
+                signature,
+                attributes,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    encoder::encode_integrity_block(&signature_stack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{Bundle, Exchange, Request, Response, Version};
+
+    fn build_bundle() -> Result<Bundle> {
+        Bundle::builder()
+            .version(Version::VersionB2)
+            .exchange(Exchange {
+                request: Request::get("isolated-app://example/").body(())?,
+                response: Response::new(b"hello".to_vec().into()),
+            })
+            .build()
+    }
+
+    #[test]
+    fn sign_and_verify() -> Result<()> {
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let bytes = build_bundle()?.encode_signed(std::slice::from_ref(&signing_key))?;
+        assert!(has_integrity_block(&bytes));
+
+        let bundle = Bundle::from_bytes(bytes)?;
+        assert_eq!(bundle.exchanges().len(), 1);
+        let integrity_block = bundle.integrity_block().as_ref().unwrap();
+        assert_eq!(integrity_block.signature_stack().len(), 1);
+        assert_eq!(
+            integrity_block.signature_stack()[0].public_key(),
+            &signing_key.verifying_key()
+        );
+        integrity_block.verify()?;
+        integrity_block.verify_with(&signing_key.verifying_key())?;
+        assert!(integrity_block
+            .verify_with(&SigningKey::from_bytes(&[2; 32]).verifying_key())
+            .is_err());
+        assert_eq!(
+            integrity_block.web_bundle_id()?,
+            web_bundle_id(&signing_key.verifying_key())
+        );
+
+        // The web bundle itself is not affected.
+        assert!(build_bundle()?.integrity_block().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn multiple_signatures() -> Result<()> {
+        let signing_keys = [
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        ];
+        let bundle = Bundle::from_bytes(build_bundle()?.encode_signed(&signing_keys)?)?;
+        let integrity_block = bundle.integrity_block().as_ref().unwrap();
+        assert_eq!(integrity_block.signature_stack().len(), 2);
+        integrity_block.verify_with(&signing_keys[1].verifying_key())?;
+        Ok(())
+    }
+
+    #[test]
+    fn verify_tampered() -> Result<()> {
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let mut bytes = build_bundle()?.encode_signed(&[signing_key])?;
+        // Tamper the body of the response.
+        let position = bytes.windows(5).position(|w| w == b"hello").unwrap();
+        bytes[position] = b'j';
+        let bundle = Bundle::from_bytes(bytes)?;
+        assert!(bundle.integrity_block().as_ref().unwrap().verify().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn web_bundle_id_test() {
+        let public_key = VerifyingKey::from_bytes(&[
+            0x8a, 0x88, 0xe3, 0xdd, 0x74, 0x09, 0xf1, 0x95, 0xfd, 0x52, 0xdb, 0x2d, 0x3c, 0xba,
+            0x5d, 0x72, 0xca, 0x67, 0x09, 0xbf, 0x1d, 0x94, 0x12, 0x1b, 0xf3, 0x74, 0x88, 0x01,
+            0xb4, 0x0f, 0x6f, 0x5c,
+        ])
+        .unwrap();
+        assert_eq!(
+            web_bundle_id(&public_key),
+            "rkeohxlubhyzl7ks3mwtzos5olfgocn7dwkbeg7toseadnapn5oaaaic"
+        );
+    }
+}
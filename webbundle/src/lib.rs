@@ -22,13 +22,21 @@
 //!
 //! ```no_run
 //! use webbundle::Bundle;
-//! use std::io::{Read as _};
 //!
-//! let mut bytes = Vec::new();
-//! std::fs::File::open("example.wbn")?.read_to_end(&mut bytes)?;
-//! let bundle = Bundle::from_bytes(bytes)?;
+//! let bundle = Bundle::open("example.wbn")?;
 //! println!("Parsed bundle: {:#?}", bundle);
-//! # Result::Ok::<(), anyhow::Error>(())
+//! # Result::Ok::<(), webbundle::Error>(())
+//! ```
+//!
+//! ## Reading a response lazily
+//!
+//! ```no_run
+//! use webbundle::BundleReader;
+//!
+//! let mut reader = BundleReader::new(std::fs::File::open("example.wbn")?)?;
+//! let exchange = reader.get(&"https://example.com/index.html".parse()?)?;
+//! println!("Read exchange: {:#?}", exchange);
+//! # Result::Ok::<(), webbundle::Error>(())
 //! ```
 //!
 //! ## Creating a bundle from files
@@ -45,15 +53,37 @@
 //! println!("Created bundle: {:#?}", bundle);
 //! let write = std::io::BufWriter::new(std::fs::File::create("example.wbn")?);
 //! bundle.write_to(write)?;
-//! # Result::Ok::<(), anyhow::Error>(())
+//! # Result::Ok::<(), webbundle::Error>(())
 //! # };
 //! ```
 
 mod builder;
 mod bundle;
+mod cbor;
 mod decoder;
 mod encoder;
+mod error;
+mod integrity_block;
+mod manifest;
+mod options;
 mod prelude;
+mod reader;
+mod signatures;
+#[cfg(test)]
+mod test_util;
+mod validation;
+mod variants;
+mod writer;
 pub use builder::Builder;
-pub use bundle::{Body, Bundle, Exchange, Request, Response, Uri, Version};
+pub use bundle::{Body, Bundle, Bytes, Exchange, Request, Response, Uri, Version};
+pub use error::Error;
+pub use integrity_block::{web_bundle_id, IntegrityBlock, IntegritySignature};
+pub use manifest::{DisplayMode, ManifestIcon, WebAppManifest};
+pub use options::{DecoderOptions, EncoderOptions, HeaderPolicy, Limits, ParseMode};
 pub use prelude::Result;
+pub use reader::BundleReader;
+pub use signatures::{
+    Authority, ResourceIntegrity, Signatures, SignedSubset, Signer, SubsetHash, VouchedSubset,
+};
+pub use validation::{Issue, ValidationReport};
+pub use writer::BundleWriter;
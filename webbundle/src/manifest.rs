@@ -0,0 +1,250 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bundle::{Bundle, Uri};
+use crate::prelude::*;
+use serde::Deserialize;
+use url::Url;
+
+/// The file names which [`Builder::exchanges_from_dir`](crate::Builder::exchanges_from_dir)
+/// detects as a manifest, in the order of preference.
+pub(crate) const MANIFEST_FILE_NAMES: [&str; 2] = ["manifest.webmanifest", "manifest.json"];
+
+/// Represents a [Web App Manifest](https://www.w3.org/TR/appmanifest/).
+///
+/// Only the members which matter to a bundle are parsed. URLs are resolved
+/// against the URL of the manifest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebAppManifest {
+    pub name: Option<String>,
+    pub start_url: Option<Uri>,
+    /// The navigation scope. Defaults to the directory of `start_url`.
+    pub scope: Option<Uri>,
+    pub display: DisplayMode,
+    pub icons: Vec<ManifestIcon>,
+}
+
+/// The preferred display mode of a web app.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisplayMode {
+    Fullscreen,
+    Standalone,
+    MinimalUi,
+    /// The default, which is also used for an unknown display mode.
+    #[default]
+    Browser,
+}
+
+/// An icon of a web app.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestIcon {
+    pub src: Uri,
+    pub sizes: Option<String>,
+    pub mime_type: Option<String>,
+    pub purpose: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawManifest {
+    name: Option<String>,
+    start_url: Option<String>,
+    scope: Option<String>,
+    display: Option<String>,
+    #[serde(default)]
+    icons: Vec<RawIcon>,
+}
+
+#[derive(Deserialize)]
+struct RawIcon {
+    src: String,
+    sizes: Option<String>,
+    #[serde(rename = "type")]
+    mime_type: Option<String>,
+    purpose: Option<String>,
+}
+
+fn invalid(message: impl std::fmt::Display) -> Error {
+    Error::InvalidManifest(message.to_string())
+}
+
+fn resolve(base: &Url, url: &str) -> Result<Uri> {
+    let url = base
+        .join(url)
+        .map_err(|err| invalid(format!("{}: {}", err, url)))?;
+    url.as_str()
+        .parse()
+        .map_err(|err| invalid(format!("{}: {}", err, url)))
+}
+
+/// Returns true if `url` is within `scope`: it has the same origin and its
+/// path starts with the path of `scope`.
+fn within_scope(url: &Uri, scope: &Uri) -> bool {
+    url.scheme() == scope.scheme()
+        && url.authority() == scope.authority()
+        && url.path().starts_with(scope.path())
+}
+
+impl WebAppManifest {
+    /// Parses the JSON of a manifest located at `manifest_url`.
+    pub fn parse(json: &[u8], manifest_url: &Uri) -> Result<WebAppManifest> {
+        let raw: RawManifest = serde_json::from_slice(json).map_err(invalid)?;
+        let base = Url::parse(&manifest_url.to_string()).map_err(invalid)?;
+        let start_url = raw
+            .start_url
+            .map(|start_url| resolve(&base, &start_url))
+            .transpose()?;
+        let scope = match (raw.scope, &start_url) {
+            (Some(scope), _) => Some(resolve(&base, &scope)?),
+            (None, Some(start_url)) => Some(resolve(&Url::parse(&start_url.to_string())?, ".")?),
+            (None, None) => None,
+        };
+        let display = match raw.display.as_deref() {
+            Some("fullscreen") => DisplayMode::Fullscreen,
+            Some("standalone") => DisplayMode::Standalone,
+            Some("minimal-ui") => DisplayMode::MinimalUi,
+            _ => DisplayMode::Browser,
+        };
+        let icons = raw
+            .icons
+            .into_iter()
+            .map(|icon| {
+                Ok(ManifestIcon {
+                    src: resolve(&base, &icon.src)?,
+                    sizes: icon.sizes,
+                    mime_type: icon.mime_type,
+                    purpose: icon.purpose,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(WebAppManifest {
+            name: raw.name,
+            start_url,
+            scope,
+            display,
+            icons,
+        })
+    }
+
+    /// Checks that the manifest has `start_url` or `name`, and that
+    /// `start_url`, if any, is within `scope` and is in the given bundle.
+    pub fn validate(&self, bundle: &Bundle) -> Result<()> {
+        let start_url = match &self.start_url {
+            Some(start_url) => start_url,
+            None if self.name.is_some() => return Ok(()),
+            None => return Err(invalid("No start_url or name")),
+        };
+        if let Some(scope) = &self.scope {
+            ensure!(
+                within_scope(start_url, scope),
+                invalid(format!(
+                    "start_url {} is not within the scope {}",
+                    start_url, scope
+                ))
+            );
+        }
+        ensure!(
+            bundle.contains(start_url),
+            invalid(format!("start_url {} is not in the bundle", start_url))
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{Exchange, Request, Response, Version};
+
+    #[test]
+    fn parse() -> Result<()> {
+        let manifest = WebAppManifest::parse(
+            br#"{
+                "name": "App",
+                "start_url": "index.html?pwa",
+                "display": "standalone",
+                "icons": [{"src": "/icon.png", "sizes": "192x192", "type": "image/png"}],
+                "theme_color": "red"
+            }"#,
+            &"https://example.com/app/manifest.json".parse()?,
+        )?;
+        assert_eq!(manifest.name.as_deref(), Some("App"));
+        assert_eq!(
+            manifest.start_url,
+            Some("https://example.com/app/index.html?pwa".parse()?)
+        );
+        assert_eq!(manifest.scope, Some("https://example.com/app/".parse()?));
+        assert_eq!(manifest.display, DisplayMode::Standalone);
+        assert_eq!(manifest.icons[0].src, "https://example.com/icon.png");
+        assert_eq!(manifest.icons[0].mime_type.as_deref(), Some("image/png"));
+
+        let manifest = WebAppManifest::parse(b"{}", &"https://example.com/m.json".parse()?)?;
+        assert_eq!(manifest.start_url, None);
+        assert_eq!(manifest.display, DisplayMode::Browser);
+        assert!(matches!(
+            WebAppManifest::parse(b"[]", &"https://example.com/m.json".parse()?),
+            Err(Error::InvalidManifest(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn validate() -> Result<()> {
+        let bundle = |json: &'static [u8]| -> Result<Bundle> {
+            Bundle::builder()
+                .version(Version::VersionB1)
+                .primary_url("https://example.com/app/".parse()?)
+                .manifest("https://example.com/app/manifest.json".parse()?)
+                .exchange(Exchange {
+                    request: Request::get("https://example.com/app/manifest.json").body(())?,
+                    response: Response::new(json.into()),
+                })
+                .exchange(Exchange {
+                    request: Request::get("https://example.com/app/").body(())?,
+                    response: Response::new(Default::default()),
+                })
+                .build()
+        };
+        let valid = bundle(br#"{"start_url": "./", "scope": "/app/"}"#)?;
+        let manifest = valid.web_app_manifest()?.unwrap();
+        manifest.validate(&valid)?;
+        assert!(Bundle::validate(valid.encode()?)?.is_valid());
+        // A manifest without start_url, as the builder detects one by its name.
+        let name_only = bundle(br#"{"name": "App"}"#)?;
+        name_only
+            .web_app_manifest()?
+            .unwrap()
+            .validate(&name_only)?;
+        assert!(Bundle::validate(name_only.encode()?)?.is_valid());
+
+        for json in [
+            &br#"{}"#[..],
+            br#"{"start_url": "/other/", "scope": "/app/"}"#,
+            br#"{"start_url": "https://example.org/app/"}"#,
+            br#"{"start_url": "missing.html"}"#,
+            br#"[]"#,
+        ] {
+            let invalid = bundle(json)?;
+            let report = Bundle::validate(invalid.encode()?)?;
+            assert!(matches!(
+                report.issues(),
+                [crate::Issue::InvalidManifest { .. }]
+            ));
+            if let Ok(manifest) = WebAppManifest::parse(json, invalid.manifest().as_ref().unwrap())
+            {
+                assert!(manifest.validate(&valid).is_err());
+            }
+        }
+        Ok(())
+    }
+}
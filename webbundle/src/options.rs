@@ -0,0 +1,166 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Resource limits which are enforced while parsing a bundle.
+///
+/// A bundle which exceeds any of these limits is rejected with
+/// [`Error::LimitExceeded`](crate::Error::LimitExceeded).
+///
+/// # Examples
+///
+/// ```no_run
+/// use webbundle::{Bundle, Limits};
+/// # let bytes: Vec<u8> = unimplemented!();
+/// let limits = Limits::default().max_exchanges(100).max_body_size(1 << 20);
+/// let bundle = Bundle::from_bytes_with_limits(bytes, &limits)?;
+/// # Result::Ok::<(), webbundle::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub(crate) max_exchanges: usize,
+    pub(crate) max_headers: usize,
+    pub(crate) max_body_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_exchanges: 100_000,
+            max_headers: 1_000,
+            max_body_size: 1 << 30,
+        }
+    }
+}
+
+impl Limits {
+    /// Sets the maximum number of exchanges in the index section.
+    /// The default is 100,000.
+    pub fn max_exchanges(mut self, max_exchanges: usize) -> Self {
+        self.max_exchanges = max_exchanges;
+        self
+    }
+
+    /// Sets the maximum number of headers in a response, including `:status`.
+    /// The default is 1,000.
+    pub fn max_headers(mut self, max_headers: usize) -> Self {
+        self.max_headers = max_headers;
+        self
+    }
+
+    /// Sets the maximum size of a response body in bytes. The default is 1 GiB.
+    pub fn max_body_size(mut self, max_body_size: u64) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+}
+
+/// How strictly a bundle is parsed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Fails on malformed input, and tolerates problems which don't prevent
+    /// parsing, such as a wrong length field. This is the default.
+    #[default]
+    Normal,
+    /// Fails on any [`Issue`](crate::Issue), such as an unknown critical
+    /// section, duplicate URLs and non-canonical CBOR.
+    Strict,
+    /// Recovers from malformed responses and optional sections as far as
+    /// possible, skipping them and reporting them as [`Issue`](crate::Issue)s.
+    Lenient,
+}
+
+/// What to do with a response header which a bundle must not have, such as
+/// a hop-by-hop header like `Connection` or a stateful header like
+/// `Set-Cookie`.
+///
+/// Header names are always lowercase, because a header with an uppercase name
+/// fails parsing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    /// Rejects the header. [`Builder::build`](crate::Builder::build) fails,
+    /// and the parser reports an [`Issue::ForbiddenHeader`](crate::Issue).
+    /// This is the default.
+    #[default]
+    Reject,
+    /// Removes the header.
+    Strip,
+    /// Keeps the header.
+    Allow,
+}
+
+/// Options for parsing a bundle.
+///
+/// # Examples
+///
+/// ```no_run
+/// use webbundle::{Bundle, DecoderOptions, ParseMode};
+/// # let bytes: Vec<u8> = unimplemented!();
+/// let options = DecoderOptions::default().mode(ParseMode::Lenient);
+/// let (bundle, report) = Bundle::from_bytes_with_options(bytes, &options)?;
+/// for issue in report.issues() {
+///     println!("{}", issue);
+/// }
+/// # Result::Ok::<(), webbundle::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecoderOptions {
+    pub(crate) mode: ParseMode,
+    pub(crate) limits: Limits,
+    pub(crate) header_policy: HeaderPolicy,
+}
+
+impl DecoderOptions {
+    /// Sets the parse mode.
+    pub fn mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the resource limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the policy for forbidden response headers.
+    pub fn header_policy(mut self, header_policy: HeaderPolicy) -> Self {
+        self.header_policy = header_policy;
+        self
+    }
+}
+
+/// Options for encoding a bundle.
+///
+/// # Examples
+///
+/// ```
+/// use webbundle::{Bundle, EncoderOptions, Version};
+/// let bundle = Bundle::builder().version(Version::VersionB2).build()?;
+/// let options = EncoderOptions::default().dedup_responses(true);
+/// let bytes = bundle.encode_with_options(&options)?;
+/// # Result::Ok::<(), webbundle::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncoderOptions {
+    pub(crate) dedup_responses: bool,
+}
+
+impl EncoderOptions {
+    /// Writes identical responses, both headers and body, only once. Their
+    /// index entries point at the same response. The default is false.
+    pub fn dedup_responses(mut self, dedup_responses: bool) -> Self {
+        self.dedup_responses = dedup_responses;
+        self
+    }
+}
@@ -12,4 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub use anyhow::{bail, ensure, Context as _, Result};
+pub use crate::error::Error;
+
+/// A specialized `Result` type for this crate.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Returns the given error if the condition is not satisfied.
+macro_rules! ensure {
+    ($cond:expr, $err:expr $(,)?) => {
+        if !$cond {
+            return Err($err.into());
+        }
+    };
+}
+
+pub(crate) use ensure;
@@ -0,0 +1,302 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bundle::{self, Exchange, Request, Uri, Version};
+use crate::decoder::{self, Metadata, ResponseLocation};
+use crate::integrity_block;
+use crate::options::{DecoderOptions, Limits};
+use crate::prelude::*;
+use crate::signatures::Signatures;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+/// The length of the prefix which is read first to parse a header.
+const INITIAL_HEADER_READ_LENGTH: u64 = 4096;
+/// The maximum length of the prefix which is read to parse a header.
+const MAX_HEADER_READ_LENGTH: u64 = 1 << 20;
+/// The maximum length of a response, in addition to the maximum body size,
+/// which is read to parse the response.
+const MAX_RESPONSE_OVERHEAD: u64 = 1 << 20;
+
+/// Reads a bundle lazily.
+///
+/// Only the metadata, such as the index section, is parsed when a reader is
+/// created. A response is read from the underlying reader on demand.
+///
+/// An integrity block, if any, is skipped. Use [`Bundle`](crate::Bundle) to
+/// verify it.
+#[derive(Debug)]
+pub struct BundleReader<R> {
+    reader: R,
+    /// The offset where the web bundle starts, after an integrity block.
+    offset: u64,
+    metadata: Metadata,
+    /// Maps a URL to the first index entry for it.
+    url_index: HashMap<String, usize>,
+    options: DecoderOptions,
+}
+
+impl<R: Read + Seek> BundleReader<R> {
+    /// Creates a reader by parsing the metadata of a bundle.
+    pub fn new(reader: R) -> Result<BundleReader<R>> {
+        BundleReader::with_limits(reader, Limits::default())
+    }
+
+    /// Creates a reader with the given resource limits, which are also applied
+    /// when a response is read.
+    pub fn with_limits(reader: R, limits: Limits) -> Result<BundleReader<R>> {
+        BundleReader::with_options(reader, DecoderOptions::default().limits(limits))
+    }
+
+    /// Creates a reader with the given options. Issues are not collected, but
+    /// are errors in [`ParseMode::Strict`](crate::ParseMode::Strict).
+    pub fn with_options(mut reader: R, options: DecoderOptions) -> Result<BundleReader<R>> {
+        let prefix = read_at(
+            &mut reader,
+            0,
+            2 + integrity_block::INTEGRITY_BLOCK_MAGIC_BYTES.len() as u64,
+        )?;
+        let offset = if integrity_block::has_integrity_block(&prefix) {
+            read_prefix(&mut reader, 0, decoder::parse_integrity_block)?.1
+        } else {
+            0
+        };
+        let section_offsets = read_prefix(&mut reader, offset, decoder::parse_section_offsets)?;
+        // The responses section is the last section.
+        let metadata_length = section_offsets.last().unwrap().offset;
+        let stream_length = reader.seek(SeekFrom::End(0))?;
+        ensure!(
+            offset.saturating_add(metadata_length) <= stream_length,
+            Error::OutOfRange {
+                offset,
+                length: metadata_length,
+            }
+        );
+        let metadata = decoder::parse_metadata(
+            &read_exact_at(&mut reader, offset, metadata_length)?,
+            &options,
+        )?;
+        let mut url_index = HashMap::with_capacity(metadata.requests.len());
+        for (i, entry) in metadata.requests.iter().enumerate() {
+            url_index
+                .entry(bundle::url_key(entry.request.uri()))
+                .or_insert(i);
+        }
+        Ok(BundleReader {
+            reader,
+            offset,
+            metadata,
+            url_index,
+            options,
+        })
+    }
+
+    /// Gets the version.
+    pub fn version(&self) -> &Version {
+        &self.metadata.version
+    }
+
+    /// Gets the primary url.
+    pub fn primary_url(&self) -> &Option<Uri> {
+        &self.metadata.primary_url
+    }
+
+    /// Gets the manifest.
+    pub fn manifest(&self) -> &Option<Uri> {
+        &self.metadata.manifest
+    }
+
+    /// Gets the signatures section.
+    pub fn signatures(&self) -> &Option<Signatures> {
+        &self.metadata.signatures
+    }
+
+    /// Returns an iterator over the URLs of the exchanges, in the order of the
+    /// index section.
+    pub fn uris(&self) -> impl Iterator<Item = &Uri> {
+        self.metadata
+            .requests
+            .iter()
+            .map(|entry| entry.request.uri())
+    }
+
+    /// Returns true if the bundle has an exchange for the given URL.
+    pub fn contains(&self, uri: &Uri) -> bool {
+        self.url_index.contains_key(&bundle::url_key(uri))
+    }
+
+    /// Reads the exchange for the given URL. If there are several variants for
+    /// the URL, the first one is returned.
+    pub fn get(&mut self, uri: &Uri) -> Result<Option<Exchange>> {
+        let entry = match self.url_index.get(&bundle::url_key(uri)) {
+            Some(&i) => &self.metadata.requests[i],
+            None => return Ok(None),
+        };
+        let uri = entry.request.uri();
+        let mut request = Request::get(uri.clone()).body(())?;
+        *request.headers_mut() = entry.request.headers().clone();
+        let ResponseLocation { offset, length } = entry.response_location;
+        // The length comes from the index section, and is checked before
+        // reading the response.
+        let max_length = self
+            .options
+            .limits
+            .max_body_size
+            .saturating_add(MAX_RESPONSE_OVERHEAD);
+        ensure!(
+            length <= max_length,
+            Error::LimitExceeded(format!(
+                "The response for {} is larger than {} bytes",
+                uri, max_length
+            ))
+        );
+        let offset = self.offset.saturating_add(offset);
+        let bytes = read_exact_at(&mut self.reader, offset, length)?;
+        let response = decoder::parse_response(bytes.into(), offset, uri, &self.options)?;
+        Ok(Some(Exchange { request, response }))
+    }
+
+    /// Unwraps this reader, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Reads at most `length` bytes at `offset`.
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, length: u64) -> Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    reader.by_ref().take(length).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Reads exactly `length` bytes at `offset`.
+fn read_exact_at<R: Read + Seek>(reader: &mut R, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let bytes = read_at(reader, offset, length)?;
+    ensure!(
+        bytes.len() as u64 == length,
+        Error::OutOfRange { offset, length }
+    );
+    Ok(bytes)
+}
+
+/// Parses a prefix starting at `offset`, reading a longer prefix until `parse`
+/// succeeds because the length of a header is unknown in advance.
+fn read_prefix<R: Read + Seek, T>(
+    reader: &mut R,
+    offset: u64,
+    parse: impl Fn(&[u8]) -> Result<T>,
+) -> Result<T> {
+    let mut length = INITIAL_HEADER_READ_LENGTH;
+    loop {
+        let bytes = read_at(reader, offset, length)?;
+        match parse(&bytes) {
+            Ok(value) => return Ok(value),
+            Err(err) if (bytes.len() as u64) < length || length >= MAX_HEADER_READ_LENGTH => {
+                return Err(err)
+            }
+            Err(_) => length *= 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{Bundle, Response};
+    use std::io::Cursor;
+
+    fn build_bundle(version: Version, primary_url: &str) -> Result<Bundle> {
+        Bundle::builder()
+            .version(version)
+            .primary_url(primary_url.parse()?)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/a").body(())?,
+                response: Response::new(b"a".to_vec().into()),
+            })
+            .exchange(Exchange {
+                request: Request::get("https://example.com/b").body(())?,
+                response: Response::new(b"b".to_vec().into()),
+            })
+            .build()
+    }
+
+    fn assert_read(bytes: Vec<u8>, primary_url: &str) -> Result<()> {
+        let mut reader = BundleReader::new(Cursor::new(bytes))?;
+        assert_eq!(reader.primary_url(), &Some(primary_url.parse()?));
+        assert_eq!(
+            reader.uris().map(|uri| uri.to_string()).collect::<Vec<_>>(),
+            ["https://example.com/a", "https://example.com/b"]
+        );
+        let exchange = reader.get(&"https://example.com/b".parse()?)?.unwrap();
+        assert_eq!(exchange.request.uri(), "https://example.com/b");
+        assert_eq!(exchange.response.body(), &b"b"[..]);
+        // The request has the URL in the bundle.
+        let exchange = reader.get(&"HTTPS://EXAMPLE.com:443/b".parse()?)?.unwrap();
+        assert_eq!(exchange.request.uri(), "https://example.com/b");
+        assert!(reader.get(&"https://example.com/c".parse()?)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn read() -> Result<()> {
+        for version in [Version::VersionB1, Version::VersionB2, Version::Version1] {
+            let bundle = build_bundle(version, "https://example.com/a")?;
+            assert_read(bundle.encode()?, "https://example.com/a")?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_long_header() -> Result<()> {
+        let primary_url = format!("https://example.com/{}", "a".repeat(10_000));
+        let bundle = build_bundle(Version::VersionB1, &primary_url)?;
+        assert_read(bundle.encode()?, &primary_url)
+    }
+
+    #[test]
+    fn read_signed() -> Result<()> {
+        let bundle = build_bundle(Version::VersionB2, "https://example.com/a")?;
+        let bytes = bundle.encode_signed(&[ed25519_dalek::SigningKey::from_bytes(&[1; 32])])?;
+        assert_read(bytes, "https://example.com/a")
+    }
+
+    #[test]
+    fn read_truncated() -> Result<()> {
+        let mut bytes = build_bundle(Version::Version1, "https://example.com/a")?.encode()?;
+        bytes.truncate(bytes.len() - 12);
+        let mut reader = BundleReader::new(Cursor::new(bytes))?;
+        assert!(reader.get(&"https://example.com/a".parse()?)?.is_some());
+        assert!(reader.get(&"https://example.com/b".parse()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_large_response() -> Result<()> {
+        let bundle = Bundle::builder()
+            .version(Version::VersionB2)
+            .exchange(Exchange {
+                request: Request::get("https://example.com/a").body(())?,
+                response: Response::new(vec![0; 2 << 20].into()),
+            })
+            .build()?;
+        let limits = Limits::default().max_body_size(1 << 10);
+        let mut reader = BundleReader::with_limits(Cursor::new(bundle.encode()?), limits)?;
+        assert!(matches!(
+            reader.get(&"https://example.com/a".parse()?),
+            Err(Error::LimitExceeded(_))
+        ));
+        Ok(())
+    }
+}
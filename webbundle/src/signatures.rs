@@ -0,0 +1,734 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for the signatures section.
+//!
+//! See [Signatures section](https://wicg.github.io/webpackage/draft-yasskin-wpack-bundled-exchanges.html#signatures-section).
+
+use crate::bundle::{self, Exchange, Response, Uri};
+use crate::decoder;
+use crate::encoder;
+use crate::prelude::*;
+use crate::variants::{self, Variants};
+use data_encoding::BASE64;
+use http::header::HeaderValue;
+use p256::ecdsa::signature::{Signer as _, Verifier as _};
+use p256::ecdsa::{DerSignature, SigningKey, VerifyingKey};
+use p256::pkcs8::DecodePublicKey as _;
+use sha2::{Digest as _, Sha256};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom as _, TryInto as _};
+use x509_cert::der::{Decode as _, Encode as _};
+
+/// The header which holds the integrity of a payload.
+pub(crate) const DIGEST: &str = "digest";
+
+/// Represents the signatures section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signatures {
+    pub authorities: Vec<Authority>,
+    pub vouched_subsets: Vec<VouchedSubset>,
+}
+
+/// Represents an augmented certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority {
+    /// A DER-encoded X.509 certificate.
+    pub cert: Vec<u8>,
+    pub ocsp: Option<Vec<u8>>,
+    pub sct: Option<Vec<u8>>,
+}
+
+/// Represents a subset of the bundle vouched by an authority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VouchedSubset {
+    /// An index into the authorities.
+    pub authority: u64,
+    /// The signature of `signed`.
+    pub sig: Vec<u8>,
+    /// The CBOR-encoded [`SignedSubset`].
+    pub signed: Vec<u8>,
+}
+
+/// Represents the contents of a vouched subset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedSubset {
+    pub validity_url: Uri,
+    pub auth_sha256: Vec<u8>,
+    pub date: u64,
+    pub expires: u64,
+    pub subset_hashes: Vec<SubsetHash>,
+}
+
+/// Represents the hashes of the responses for a URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubsetHash {
+    pub uri: Uri,
+    pub variants_value: Vec<u8>,
+    /// One for each variant key, or just one if `variants_value` is empty.
+    pub resource_integrities: Vec<ResourceIntegrity>,
+}
+
+/// Represents the integrity of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceIntegrity {
+    /// The SHA-256 hash of the CBOR-encoded response headers.
+    pub header_sha256: Vec<u8>,
+    /// The name of the response header which holds the payload's integrity.
+    pub payload_integrity_header: String,
+}
+
+impl Signatures {
+    /// Verifies each vouched subset, returning the verified signed subsets.
+    ///
+    /// This checks that:
+    ///
+    /// - a signature is made by the key of the authority's certificate,
+    /// - the hashes of the signed subset match the given exchanges.
+    ///
+    /// Whether the certificates should be trusted, and whether signatures
+    /// are valid at the current time, is up to the caller.
+    pub fn verify(&self, exchanges: &[Exchange]) -> Result<Vec<SignedSubset>> {
+        self.vouched_subsets
+            .iter()
+            .map(|vouched_subset| {
+                let authority = self
+                    .authorities
+                    .get(vouched_subset.authority as usize)
+                    .ok_or_else(|| Error::Signature("authority is out of range".to_string()))?;
+                vouched_subset.verify(authority, exchanges)
+            })
+            .collect()
+    }
+}
+
+impl VouchedSubset {
+    fn verify(&self, authority: &Authority, exchanges: &[Exchange]) -> Result<SignedSubset> {
+        let signed_subset = decoder::parse_signed_subset(&self.signed)?;
+        ensure!(
+            signed_subset.auth_sha256 == Sha256::digest(&authority.cert).as_slice(),
+            Error::Signature("auth-sha256 mismatch".to_string())
+        );
+        ensure!(
+            signed_subset.date < signed_subset.expires,
+            Error::Signature("Signature expires before its date".to_string())
+        );
+        let signature = DerSignature::from_bytes(&self.sig).map_err(signature_error)?;
+        authority
+            .verifying_key()?
+            .verify(&self.signed, &signature)
+            .map_err(signature_error)?;
+        for subset_hash in &signed_subset.subset_hashes {
+            subset_hash.verify(exchanges)?;
+        }
+        Ok(signed_subset)
+    }
+}
+
+impl Authority {
+    /// Creates an authority from a DER-encoded certificate.
+    pub fn new(cert: Vec<u8>) -> Authority {
+        Authority {
+            cert,
+            ocsp: None,
+            sct: None,
+        }
+    }
+
+    fn verifying_key(&self) -> Result<VerifyingKey> {
+        let cert = x509_cert::Certificate::from_der(&self.cert).map_err(signature_error)?;
+        let spki = cert
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(signature_error)?;
+        VerifyingKey::from_public_key_der(&spki).map_err(signature_error)
+    }
+}
+
+impl SubsetHash {
+    fn verify(&self, exchanges: &[Exchange]) -> Result<()> {
+        let key = bundle::url_key(&self.uri);
+        let exchanges = exchanges
+            .iter()
+            .filter(|exchange| bundle::url_key(exchange.request.uri()) == key)
+            .collect::<Vec<_>>();
+        ensure!(
+            !exchanges.is_empty(),
+            Error::Signature(format!("No response for signed url: {}", self.uri))
+        );
+        if self.variants_value.is_empty() {
+            ensure!(
+                exchanges.len() == 1 && self.resource_integrities.len() == 1,
+                Error::Signature(format!("Unexpected number of responses: {}", self.uri))
+            );
+            return self.resource_integrities[0].verify(&exchanges[0].response);
+        }
+        let variants =
+            Variants::parse(std::str::from_utf8(&self.variants_value).map_err(signature_error)?)?;
+        // The number of keys is checked before walking them, because the
+        // Variants value may have an enormous number of them.
+        ensure!(
+            variants.keys_len() == Some(self.resource_integrities.len() as u64),
+            Error::Signature(format!(
+                "Unexpected number of resource integrities: {}",
+                self.uri
+            ))
+        );
+        for (i, resource_integrity) in self.resource_integrities.iter().enumerate() {
+            let key = variants.key_at(i as u64);
+            let exchange = exchanges
+                .iter()
+                .find(|exchange| {
+                    variants::exchange_variant_keys(exchange)
+                        .map(|keys| keys.contains(&key))
+                        .unwrap_or(false)
+                })
+                .ok_or_else(|| {
+                    Error::Signature(format!(
+                        "No response for variant key {:?}: {}",
+                        key, self.uri
+                    ))
+                })?;
+            resource_integrity.verify(&exchange.response)?;
+        }
+        Ok(())
+    }
+}
+
+impl ResourceIntegrity {
+    fn new(response: &Response) -> Result<ResourceIntegrity> {
+        ensure!(
+            response.headers().contains_key(DIGEST),
+            Error::Signature("A signed response must have a digest header".to_string())
+        );
+        Ok(ResourceIntegrity {
+            header_sha256: Sha256::digest(encoder::encode_headers(response)?).to_vec(),
+            payload_integrity_header: DIGEST.to_string(),
+        })
+    }
+
+    fn verify(&self, response: &Response) -> Result<()> {
+        ensure!(
+            self.header_sha256 == Sha256::digest(encoder::encode_headers(response)?).as_slice(),
+            Error::Signature("header-sha256 mismatch".to_string())
+        );
+        ensure!(
+            self.payload_integrity_header == DIGEST,
+            Error::Signature(format!(
+                "Unsupported payload integrity header: {}",
+                self.payload_integrity_header
+            ))
+        );
+        let digest = response
+            .headers()
+            .get(DIGEST)
+            .ok_or_else(|| Error::Signature("No digest header".to_string()))?
+            .to_str()?;
+        verify_digest(digest, response.body())
+    }
+}
+
+fn signature_error(err: impl std::fmt::Display) -> Error {
+    Error::Signature(err.to_string())
+}
+
+/// Verifies a `Digest` header value against the payload.
+///
+/// `sha-256` and `mi-sha256-03` (Merkle Integrity) are supported.
+fn verify_digest(digest: &str, payload: &[u8]) -> Result<()> {
+    for member in digest.split(',') {
+        let (algorithm, value) = member
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| Error::Signature("Invalid digest header".to_string()))?;
+        let expected = BASE64.decode(value.as_bytes()).map_err(signature_error)?;
+        let actual = match algorithm.to_ascii_lowercase().as_str() {
+            "sha-256" => Sha256::digest(payload).to_vec(),
+            "mi-sha256-03" => mi_sha256(payload)?.to_vec(),
+            _ => continue,
+        };
+        ensure!(
+            expected == actual,
+            Error::Signature("Payload digest mismatch".to_string())
+        );
+        return Ok(());
+    }
+    Err(Error::Signature(format!(
+        "No supported digest algorithm: {}",
+        digest
+    )))
+}
+
+/// Computes the top-level proof of a `mi-sha256-03` encoded payload, checking
+/// the proofs embedded in it.
+///
+/// See [Merkle Integrity Content Encoding](https://tools.ietf.org/html/draft-thomson-http-mice-03).
+fn mi_sha256(encoded: &[u8]) -> Result<[u8; 32]> {
+    if encoded.is_empty() {
+        return Ok(Sha256::digest([0]).into());
+    }
+    ensure!(
+        encoded.len() > 8,
+        Error::Signature("Invalid mi-sha256 payload".to_string())
+    );
+    let (record_size, mut rest) = encoded.split_at(8);
+    let record_size = u64::from_be_bytes(record_size.try_into().unwrap());
+    ensure!(
+        record_size > 0,
+        Error::Signature("Invalid mi-sha256 record size".to_string())
+    );
+    let record_size = usize::try_from(record_size).unwrap_or(usize::MAX);
+
+    // (record, the proof of the next record)
+    let mut records = Vec::new();
+    while rest.len() > record_size {
+        ensure!(
+            rest.len() > record_size + Sha256::output_size(),
+            Error::Signature("Invalid mi-sha256 payload".to_string())
+        );
+        let (record, next) = rest.split_at(record_size);
+        let (proof, next) = next.split_at(Sha256::output_size());
+        records.push((record, Some(proof)));
+        rest = next;
+    }
+    records.push((rest, None));
+
+    let mut proof: Option<[u8; 32]> = None;
+    for (record, embedded_proof) in records.into_iter().rev() {
+        let mut hasher = Sha256::new();
+        hasher.update(record);
+        match proof {
+            Some(next) => {
+                ensure!(
+                    embedded_proof == Some(&next[..]),
+                    Error::Signature("mi-sha256 proof mismatch".to_string())
+                );
+                hasher.update(next);
+                hasher.update([1]);
+            }
+            None => hasher.update([0]),
+        }
+        proof = Some(hasher.finalize().into());
+    }
+    Ok(proof.unwrap())
+}
+
+/// Signs exchanges with an ECDSA P-256 key.
+///
+/// # Examples
+///
+/// ```no_run
+/// use webbundle::{Authority, Bundle, Signer, Version};
+/// # let signing_key: p256::ecdsa::SigningKey = unimplemented!();
+/// # let cert: Vec<u8> = unimplemented!();
+/// let signer = Signer::new(
+///     signing_key,
+///     vec![Authority::new(cert)],
+///     "https://example.com/resource.validity".parse()?,
+///     1_600_000_000,
+///     1_600_000_000 + 7 * 24 * 60 * 60,
+/// );
+/// let bundle = Bundle::builder()
+///     .version(Version::VersionB1)
+///     .primary_url("https://example.com/".parse()?)
+///     .sign(signer)
+///     .build()?;
+/// # Result::Ok::<(), webbundle::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Signer {
+    signing_key: SigningKey,
+    authorities: Vec<Authority>,
+    validity_url: Uri,
+    date: u64,
+    expires: u64,
+}
+
+impl Signer {
+    /// Creates a signer.
+    ///
+    /// The first of `authorities` must hold the certificate for `signing_key`.
+    /// `date` and `expires` are seconds since the UNIX epoch.
+    pub fn new(
+        signing_key: SigningKey,
+        authorities: Vec<Authority>,
+        validity_url: Uri,
+        date: u64,
+        expires: u64,
+    ) -> Signer {
+        Signer {
+            signing_key,
+            authorities,
+            validity_url,
+            date,
+            expires,
+        }
+    }
+
+    /// Adds a `digest` header to each response which doesn't have one yet,
+    /// so that a payload can be verified.
+    pub(crate) fn add_digest_headers(exchanges: &mut [Exchange]) -> Result<()> {
+        for exchange in exchanges {
+            let response = &mut exchange.response;
+            if !response.headers().contains_key(DIGEST) {
+                let digest = format!(
+                    "sha-256={}",
+                    BASE64.encode(&Sha256::digest(response.body()))
+                );
+                response
+                    .headers_mut()
+                    .insert(DIGEST, HeaderValue::from_str(&digest)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Signs all the given exchanges.
+    pub(crate) fn sign(&self, exchanges: &[Exchange]) -> Result<Signatures> {
+        let authority = self
+            .authorities
+            .first()
+            .ok_or_else(|| Error::Signature("No authority".to_string()))?;
+        ensure!(
+            authority.verifying_key()? == *self.signing_key.verifying_key(),
+            Error::Signature("The certificate doesn't match the signing key".to_string())
+        );
+
+        // Group responses by URL, in the same manner as the index section.
+        let mut exchanges_by_uri = BTreeMap::<String, Vec<&Exchange>>::new();
+        for exchange in exchanges {
+            exchanges_by_uri
+                .entry(bundle::url_key(exchange.request.uri()))
+                .or_default()
+                .push(exchange);
+        }
+        let subset_hashes = exchanges_by_uri
+            .into_values()
+            .map(|exchanges| Self::subset_hash(&exchanges))
+            .collect::<Result<Vec<_>>>()?;
+
+        let signed = encoder::encode_signed_subset(&SignedSubset {
+            validity_url: self.validity_url.clone(),
+            auth_sha256: Sha256::digest(&authority.cert).to_vec(),
+            date: self.date,
+            expires: self.expires,
+            subset_hashes,
+        })?;
+        let sig: DerSignature = self.signing_key.sign(&signed);
+        Ok(Signatures {
+            authorities: self.authorities.clone(),
+            vouched_subsets: vec![VouchedSubset {
+                authority: 0,
+                sig: sig.as_bytes().to_vec(),
+                signed,
+            }],
+        })
+    }
+
+    fn subset_hash(exchanges: &[&Exchange]) -> Result<SubsetHash> {
+        let uri = exchanges[0].request.uri().clone();
+        let headers = exchanges[0].response.headers();
+        let variants = match variants::variants_of(headers)? {
+            Some(variants) => variants,
+            None => {
+                ensure!(
+                    exchanges.len() == 1,
+                    Error::InvalidBundle(format!(
+                        "Multiple responses without Variants header: {}",
+                        uri
+                    ))
+                );
+                return Ok(SubsetHash {
+                    uri,
+                    variants_value: Vec::new(),
+                    resource_integrities: vec![ResourceIntegrity::new(&exchanges[0].response)?],
+                });
+            }
+        };
+        let resource_integrities = variants
+            .keys()
+            .iter()
+            .map(|key| {
+                let exchange = exchanges
+                    .iter()
+                    .find(|exchange| {
+                        variants::exchange_variant_keys(exchange)
+                            .map(|keys| keys.contains(key))
+                            .unwrap_or(false)
+                    })
+                    .ok_or_else(|| {
+                        Error::InvalidBundle(format!(
+                            "No response for variant key {:?}: {}",
+                            key, uri
+                        ))
+                    })?;
+                ResourceIntegrity::new(&exchange.response)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(SubsetHash {
+            uri,
+            variants_value: headers[variants::VARIANTS].as_bytes().to_vec(),
+            resource_integrities,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bundle::{Bundle, Request, Version};
+    use std::str::FromStr as _;
+    use std::time::Duration;
+    use x509_cert::builder::{Builder as _, CertificateBuilder, Profile};
+    use x509_cert::name::Name;
+    use x509_cert::serial_number::SerialNumber;
+    use x509_cert::spki::SubjectPublicKeyInfoOwned;
+    use x509_cert::time::Validity;
+
+    /// Returns a signing key and its self-signed certificate.
+    pub(crate) fn signing_key_and_cert(seed: u8) -> Result<(SigningKey, Vec<u8>)> {
+        let signing_key = SigningKey::from_bytes(&[seed; 32].into()).map_err(signature_error)?;
+        let builder = CertificateBuilder::new(
+            Profile::Root,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(60 * 60)).map_err(signature_error)?,
+            Name::from_str("CN=example.com").map_err(signature_error)?,
+            SubjectPublicKeyInfoOwned::from_key(*signing_key.verifying_key())
+                .map_err(signature_error)?,
+            &signing_key,
+        )
+        .map_err(signature_error)?;
+        let cert = builder
+            .build::<DerSignature>()
+            .map_err(signature_error)?
+            .to_der()
+            .map_err(signature_error)?;
+        Ok((signing_key, cert))
+    }
+
+    pub(crate) fn signer(seed: u8) -> Result<Signer> {
+        let (signing_key, cert) = signing_key_and_cert(seed)?;
+        Ok(Signer::new(
+            signing_key,
+            vec![Authority::new(cert)],
+            "https://example.com/resource.validity".parse()?,
+            1_600_000_000,
+            1_600_000_000 + 7 * 24 * 60 * 60,
+        ))
+    }
+
+    use crate::test_util::{exchange, variant};
+
+    fn build_signed_bundle() -> Result<Bundle> {
+        Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(exchange("https://example.com/", "index")?)
+            .exchange(exchange("https://example.com/a.js", "a")?)
+            .sign(signer(1)?)
+            .build()
+    }
+
+    #[test]
+    fn sign_and_verify() -> Result<()> {
+        let bundle = Bundle::from_bytes(build_signed_bundle()?.encode()?)?;
+        let signatures = bundle.signatures().as_ref().unwrap();
+        assert_eq!(signatures.authorities.len(), 1);
+        assert_eq!(signatures.vouched_subsets.len(), 1);
+
+        let signed_subsets = bundle.verify_signatures()?;
+        assert_eq!(signed_subsets.len(), 1);
+        let signed_subset = &signed_subsets[0];
+        assert_eq!(signed_subset.date, 1_600_000_000);
+        assert_eq!(
+            signed_subset
+                .subset_hashes
+                .iter()
+                .map(|subset_hash| subset_hash.uri.to_string())
+                .collect::<Vec<_>>(),
+            vec!["https://example.com/", "https://example.com/a.js"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sign_variants_of_normalized_url() -> Result<()> {
+        let variant = |uri, key| variant(uri, "Accept-Language;en;fr", key);
+        // The variants are for one URL in the index section.
+        let bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(variant("https://example.com/", "en")?)
+            .exchange(variant("https://Example.COM:443/", "fr")?)
+            .sign(signer(1)?)
+            .build()?;
+        let signed_subsets = bundle.verify_signatures()?;
+        assert_eq!(signed_subsets[0].subset_hashes.len(), 1);
+        Bundle::from_bytes(bundle.encode()?)?.verify_signatures()?;
+        Ok(())
+    }
+
+    #[test]
+    fn sign_variants_keyed_by_request_headers() -> Result<()> {
+        // Without a Variant-Key header, the request headers name the variant key.
+        let variant = |language: &'static str| -> Result<Exchange> {
+            let mut exchange = variant("https://example.com/", "Accept-Language;en;fr", language)?;
+            exchange.response.headers_mut().remove("variant-key");
+            exchange
+                .request
+                .headers_mut()
+                .insert("accept-language", HeaderValue::from_static(language));
+            Ok(exchange)
+        };
+        let bundle = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .exchange(variant("en")?)
+            .exchange(variant("fr")?)
+            .sign(signer(1)?)
+            .build()?;
+        bundle.verify_signatures()?;
+        Bundle::from_bytes(bundle.encode()?)?.verify_signatures()?;
+        Ok(())
+    }
+
+    #[test]
+    fn verify_tampered() -> Result<()> {
+        // Tampered payload.
+        let mut bundle = build_signed_bundle()?;
+        *bundle.exchanges[1].response.body_mut() = b"b".to_vec().into();
+        assert!(bundle.verify_signatures().is_err());
+
+        // Tampered headers.
+        let mut bundle = build_signed_bundle()?;
+        bundle.exchanges[1]
+            .response
+            .headers_mut()
+            .insert("content-type", HeaderValue::from_static("text/plain"));
+        assert!(bundle.verify_signatures().is_err());
+
+        // Signed by another key.
+        let mut bundle = build_signed_bundle()?;
+        let other = Bundle::builder()
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/".parse()?)
+            .sign(signer(2)?)
+            .build()?;
+        bundle.signatures.as_mut().unwrap().authorities = other.signatures.unwrap().authorities;
+        assert!(bundle.verify_signatures().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn verify_too_many_variant_keys() -> Result<()> {
+        // 16^8 variant keys, which must not be enumerated.
+        let variants_value = (0..8)
+            .map(|axis| {
+                let values = (0..16)
+                    .map(|value| format!(";v{}", value))
+                    .collect::<String>();
+                format!("h{}{}", axis, values)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let subset_hash = SubsetHash {
+            uri: "https://example.com/".parse()?,
+            variants_value: variants_value.into_bytes(),
+            resource_integrities: Vec::new(),
+        };
+        let exchange = Exchange {
+            request: Request::get("https://example.com/").body(())?,
+            response: Response::new(Default::default()),
+        };
+        assert!(matches!(
+            subset_hash.verify(&[exchange]),
+            Err(Error::Signature(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn signer_key_mismatch() -> Result<()> {
+        let (signing_key, _) = signing_key_and_cert(1)?;
+        let (_, cert) = signing_key_and_cert(2)?;
+        let signer = Signer::new(
+            signing_key,
+            vec![Authority::new(cert)],
+            "https://example.com/resource.validity".parse()?,
+            0,
+            1,
+        );
+        assert!(signer.sign(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn digest() -> Result<()> {
+        let payload = b"hello";
+        let sha256 = format!("sha-256={}", BASE64.encode(&Sha256::digest(payload)));
+        assert!(verify_digest(&sha256, payload).is_ok());
+        assert!(verify_digest(&sha256, b"world").is_err());
+        assert!(verify_digest("md5=abc", payload).is_err());
+        Ok(())
+    }
+
+    /// Encodes a payload in `mi-sha256-03`.
+    fn mi_encode(payload: &[u8], record_size: usize) -> Vec<u8> {
+        let records = payload.chunks(record_size).collect::<Vec<_>>();
+        let mut proofs = vec![Vec::new(); records.len()];
+        let mut next: Option<Vec<u8>> = None;
+        for (i, record) in records.iter().enumerate().rev() {
+            let mut hasher = Sha256::new();
+            hasher.update(record);
+            if let Some(next) = &next {
+                proofs[i] = next.clone();
+                hasher.update(next);
+                hasher.update([1]);
+            } else {
+                hasher.update([0]);
+            }
+            next = Some(hasher.finalize().to_vec());
+        }
+        let mut encoded = (record_size as u64).to_be_bytes().to_vec();
+        for (record, proof) in records.iter().zip(proofs) {
+            encoded.extend_from_slice(record);
+            encoded.extend_from_slice(&proof);
+        }
+        encoded
+    }
+
+    #[test]
+    fn mi_sha256_test() -> Result<()> {
+        // The example in draft-thomson-http-mice-03, Section 4.1.
+        let payload = b"When I grow up, I want to be a watermelon";
+        assert_eq!(
+            BASE64.encode(&mi_sha256(&mi_encode(payload, payload.len()))?),
+            "dcRDgR2GM35DluAV13PzgnG6+pvQwPywfFvAu1UeFrs="
+        );
+
+        // Multiple records.
+        let mut encoded = mi_encode(payload, 16);
+        let top_level_proof = mi_sha256(&encoded)?;
+        let digest = format!("mi-sha256-03={}", BASE64.encode(&top_level_proof));
+        assert!(verify_digest(&digest, &encoded).is_ok());
+
+        // Tampered embedded proof.
+        encoded[8 + 16] ^= 1;
+        assert!(mi_sha256(&encoded).is_err());
+
+        assert!(mi_sha256(&encoded[..4]).is_err());
+        Ok(())
+    }
+}
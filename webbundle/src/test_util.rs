@@ -0,0 +1,37 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixtures shared by the tests.
+
+use crate::bundle::{Exchange, Request, Response};
+use crate::prelude::*;
+use http::header::HeaderValue;
+
+/// Returns an exchange for `uri` whose response has the given body.
+pub(crate) fn exchange(uri: &str, body: &str) -> Result<Exchange> {
+    Ok(Exchange {
+        request: Request::get(uri).body(())?,
+        response: Response::new(body.as_bytes().to_vec().into()),
+    })
+}
+
+/// Returns an exchange for `uri` which is the variant for `variant_key` of
+/// the given `Variants` header. The body is the variant key.
+pub(crate) fn variant(uri: &str, variants: &str, variant_key: &str) -> Result<Exchange> {
+    let mut exchange = exchange(uri, variant_key)?;
+    let headers = exchange.response.headers_mut();
+    headers.insert("variants", HeaderValue::from_str(variants)?);
+    headers.insert("variant-key", HeaderValue::from_str(variant_key)?);
+    Ok(exchange)
+}
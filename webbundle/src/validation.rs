@@ -0,0 +1,217 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bundle::Exchange;
+use crate::options::HeaderPolicy;
+use crate::prelude::*;
+use http::header::{HeaderMap, HeaderName, CONNECTION};
+use std::fmt;
+
+/// The response headers which a bundle must not have.
+const FORBIDDEN_HEADERS: [&str; 20] = [
+    // Hop-by-hop headers.
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    // Stateful headers.
+    "authentication-control",
+    "authentication-info",
+    "clear-site-data",
+    "optional-www-authenticate",
+    "proxy-authenticate",
+    "proxy-authentication-info",
+    "public-key-pins",
+    "sec-websocket-accept",
+    "set-cookie",
+    "set-cookie2",
+    "setprofile",
+    "strict-transport-security",
+    "www-authenticate",
+];
+
+/// Returns the forbidden headers in the given response headers, including
+/// the ones which the `Connection` header names.
+pub(crate) fn forbidden_headers(headers: &HeaderMap) -> Vec<HeaderName> {
+    let connection = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    headers
+        .keys()
+        .filter(|name| {
+            FORBIDDEN_HEADERS.contains(&name.as_str())
+                || connection.iter().any(|c| c == name.as_str())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Applies the policy to the response headers of an exchange which is being
+/// encoded. A forbidden header is an error in [`HeaderPolicy::Reject`].
+pub(crate) fn apply_header_policy(policy: HeaderPolicy, exchange: &mut Exchange) -> Result<()> {
+    if policy == HeaderPolicy::Allow {
+        return Ok(());
+    }
+    for name in forbidden_headers(exchange.response.headers()) {
+        ensure!(
+            policy == HeaderPolicy::Strip,
+            Error::InvalidHeaders(format!("Forbidden response header: {}", name))
+                .in_exchange(exchange.request.uri())
+        );
+        exchange.response.headers_mut().remove(name);
+    }
+    Ok(())
+}
+
+/// A structural problem of a bundle which doesn't prevent parsing it.
+///
+/// Offsets are byte offsets in the input, including an integrity block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Issue {
+    /// The trailing length field doesn't match the length of the bundle.
+    LengthMismatch { declared: u64, actual: u64 },
+    /// The trailing length field is missing or malformed.
+    InvalidLengthField { offset: u64 },
+    /// There are bytes after the trailing length field.
+    TrailingBytes { offset: u64, length: u64 },
+    /// A section is not exactly as long as its length in the section table.
+    SectionLengthMismatch {
+        name: String,
+        declared: u64,
+        actual: u64,
+    },
+    /// The responses section is malformed.
+    InvalidResponses { offset: u64, message: String },
+    /// The number of responses doesn't match the number of responses
+    /// referenced by the index section.
+    ResponsesCountMismatch { responses: u64, index: u64 },
+    /// An index entry doesn't point to a response.
+    DanglingIndexEntry { offset: u64, length: u64 },
+    /// The critical section names a section which is not understood. This
+    /// fails parsing in every mode.
+    UnknownCriticalSection { name: String },
+    /// The index section has several entries for a URL.
+    DuplicateUrl { url: String },
+    /// A CBOR item is not canonically encoded.
+    NonCanonicalCbor { offset: u64 },
+    /// A section is malformed, and is skipped in
+    /// [`ParseMode::Lenient`](crate::ParseMode::Lenient).
+    MalformedSection { name: String, message: String },
+    /// A response is malformed, and is skipped in
+    /// [`ParseMode::Lenient`](crate::ParseMode::Lenient).
+    MalformedResponse { url: String, message: String },
+    /// A response has a header which a bundle must not have. See
+    /// [`HeaderPolicy`](crate::HeaderPolicy).
+    ForbiddenHeader { url: String, name: String },
+    /// The web app manifest which the manifest section names is malformed or
+    /// fails [`WebAppManifest::validate`](crate::WebAppManifest::validate).
+    InvalidManifest { url: String, message: String },
+    /// A response is identical to another one but not shared with it, while
+    /// the bundle shares other responses. Such a bundle is not encoded into
+    /// the same bytes.
+    DuplicateResponse { offset: u64 },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::LengthMismatch { declared, actual } => write!(
+                f,
+                "The length field is {} bytes, but the bundle is {} bytes",
+                declared, actual
+            ),
+            Issue::InvalidLengthField { offset } => {
+                write!(f, "Invalid length field at offset {}", offset)
+            }
+            Issue::TrailingBytes { offset, length } => {
+                write!(f, "{} trailing bytes at offset {}", length, offset)
+            }
+            Issue::SectionLengthMismatch {
+                name,
+                declared,
+                actual,
+            } => write!(
+                f,
+                "The {} section is {} bytes, but its length is {} bytes",
+                name, actual, declared
+            ),
+            Issue::InvalidResponses { offset, message } => write!(
+                f,
+                "Invalid responses section at offset {}: {}",
+                offset, message
+            ),
+            Issue::ResponsesCountMismatch { responses, index } => write!(
+                f,
+                "The responses section has {} responses, but the index section refers to {}",
+                responses, index
+            ),
+            Issue::DanglingIndexEntry { offset, length } => {
+                write!(f, "No response at offset {} with length {}", offset, length)
+            }
+            Issue::UnknownCriticalSection { name } => {
+                write!(f, "Unknown critical section: {}", name)
+            }
+            Issue::DuplicateUrl { url } => write!(f, "Duplicate URL in the index: {}", url),
+            Issue::NonCanonicalCbor { offset } => {
+                write!(f, "Non-canonical CBOR at offset {}", offset)
+            }
+            Issue::MalformedSection { name, message } => {
+                write!(f, "Malformed {} section: {}", name, message)
+            }
+            Issue::MalformedResponse { url, message } => {
+                write!(f, "Malformed response for {}: {}", url, message)
+            }
+            Issue::ForbiddenHeader { url, name } => {
+                write!(f, "Forbidden response header {} for {}", name, url)
+            }
+            Issue::InvalidManifest { url, message } => {
+                write!(f, "Invalid web app manifest {}: {}", url, message)
+            }
+            Issue::DuplicateResponse { offset } => write!(
+                f,
+                "Response at offset {} is identical to another one but not shared",
+                offset
+            ),
+        }
+    }
+}
+
+/// The issues found while parsing a bundle.
+///
+/// See [`Bundle::validate`](crate::Bundle::validate) and
+/// [`Bundle::from_bytes_with_options`](crate::Bundle::from_bytes_with_options).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub(crate) issues: Vec<Issue>,
+}
+
+impl ValidationReport {
+    /// Returns true if no issue is found.
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Gets the issues found.
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for [HTTP Representation Variants](https://tools.ietf.org/html/draft-ietf-httpbis-variants-04).
+
+use crate::bundle::Exchange;
+use crate::prelude::*;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+pub(crate) const VARIANTS: &str = "variants";
+pub(crate) const VARIANT_KEY: &str = "variant-key";
+
+/// Represents a parsed `Variants` header value, e.g.
+/// `Accept-Language;en;fr, Accept-Encoding;gzip;br`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Variants {
+    axes: Vec<VariantAxis>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VariantAxis {
+    field_name: String,
+    available_values: Vec<String>,
+}
+
+/// A variant key, one value for each axis of `Variants`.
+pub(crate) type VariantKey = Vec<String>;
+
+impl Variants {
+    pub(crate) fn parse(value: &str) -> Result<Variants> {
+        let mut axes = Vec::new();
+        for member in value.split(',') {
+            let mut items = member.split(';').map(str::trim);
+            let field_name = items.next().unwrap().to_ascii_lowercase();
+            ensure!(
+                !field_name.is_empty(),
+                Error::InvalidHeaders(format!("Invalid Variants header: {}", value))
+            );
+            let available_values = items.map(str::to_string).collect::<Vec<_>>();
+            ensure!(
+                !available_values.is_empty() && available_values.iter().all(|v| !v.is_empty()),
+                Error::InvalidHeaders(format!("Invalid Variants header: {}", value))
+            );
+            axes.push(VariantAxis {
+                field_name,
+                available_values,
+            });
+        }
+        Ok(Variants { axes })
+    }
+
+    /// Returns the number of possible variant keys, or `None` on overflow.
+    pub(crate) fn keys_len(&self) -> Option<u64> {
+        self.axes.iter().try_fold(1u64, |n, axis| {
+            n.checked_mul(axis.available_values.len() as u64)
+        })
+    }
+
+    /// Returns all possible variant keys, in the order used by the index section.
+    pub(crate) fn keys(&self) -> Vec<VariantKey> {
+        cartesian_product(
+            &self
+                .axes
+                .iter()
+                .map(|axis| axis.available_values.clone())
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Returns the `i`-th variant key of [`keys`](Variants::keys) without
+    /// enumerating them.
+    pub(crate) fn key_at(&self, mut i: u64) -> VariantKey {
+        let mut key = self
+            .axes
+            .iter()
+            .rev()
+            .map(|axis| {
+                let len = axis.available_values.len() as u64;
+                let value = axis.available_values[(i % len) as usize].clone();
+                i /= len;
+                value
+            })
+            .collect::<Vec<_>>();
+        key.reverse();
+        key
+    }
+
+    /// Returns `true` if the given header is an axis.
+    pub(crate) fn has_axis(&self, field_name: &HeaderName) -> bool {
+        self.axes
+            .iter()
+            .any(|axis| axis.field_name == field_name.as_str())
+    }
+
+    /// Returns the request headers which name the given variant key, one
+    /// header for each axis.
+    pub(crate) fn request_headers(&self, key: &[String]) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (axis, value) in self.axes.iter().zip(key) {
+            headers.append(
+                HeaderName::from_bytes(axis.field_name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        Ok(headers)
+    }
+
+    /// Returns the variant key which the given request headers name exactly,
+    /// as [`request_headers`](Variants::request_headers) creates them.
+    pub(crate) fn key_of(&self, headers: &HeaderMap) -> Option<VariantKey> {
+        self.axes
+            .iter()
+            .map(|axis| {
+                let value = headers.get(axis.field_name.as_str())?.to_str().ok()?;
+                axis.available_values
+                    .iter()
+                    .find(|available| *available == value)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Returns variant keys sorted by the preference of the given request headers.
+    fn preferred_keys(&self, headers: &HeaderMap) -> Vec<VariantKey> {
+        cartesian_product(
+            &self
+                .axes
+                .iter()
+                .map(|axis| axis.preferred_values(headers))
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+impl VariantAxis {
+    /// Sorts available values by the preference expressed in the request
+    /// header for this axis. The first available value is used as a default
+    /// if nothing is acceptable.
+    fn preferred_values(&self, headers: &HeaderMap) -> Vec<String> {
+        let ranges = headers
+            .get_all(self.field_name.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(parse_weighted_list)
+            .collect::<Vec<_>>();
+
+        let mut preferred = self
+            .available_values
+            .iter()
+            .filter_map(|value| {
+                ranges
+                    .iter()
+                    .filter(|(range, _)| self.matches(range, value))
+                    .map(|(_, q)| *q)
+                    .fold(None, |acc: Option<f32>, q| {
+                        Some(acc.map_or(q, |a| a.max(q)))
+                    })
+                    .filter(|q| *q > 0.0)
+                    .map(|q| (value.clone(), q))
+            })
+            .collect::<Vec<_>>();
+        // Stable sort keeps the order of available values for the same weight.
+        preferred.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+
+        if preferred.is_empty() {
+            vec![self.available_values[0].clone()]
+        } else {
+            preferred.into_iter().map(|(value, _)| value).collect()
+        }
+    }
+
+    fn matches(&self, range: &str, value: &str) -> bool {
+        if range == "*" || range.eq_ignore_ascii_case(value) {
+            return true;
+        }
+        let range = range.to_ascii_lowercase();
+        let value = value.to_ascii_lowercase();
+        match self.field_name.as_str() {
+            "accept" => match (range.split_once('/'), value.split_once('/')) {
+                (Some((range_type, "*")), Some((value_type, _))) => range_type == value_type,
+                _ => false,
+            },
+            "accept-language" => {
+                // Both lookup ("en-us" => "en") and basic filtering ("en" => "en-us").
+                range.starts_with(&format!("{}-", value))
+                    || value.starts_with(&format!("{}-", range))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a header value such as `en;q=0.8, fr;q=0.5` into `(value, weight)` pairs.
+fn parse_weighted_list(value: &str) -> Vec<(String, f32)> {
+    value
+        .split(',')
+        .filter_map(|member| {
+            let mut items = member.split(';').map(str::trim);
+            let value = items.next().filter(|v| !v.is_empty())?;
+            let q = items
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value.to_string(), q))
+        })
+        .collect()
+}
+
+/// Parses a `Variant-Key` header value, e.g. `en;gzip, fr;gzip`.
+pub(crate) fn parse_variant_key(value: &str) -> Vec<VariantKey> {
+    value
+        .split(',')
+        .map(|key| key.split(';').map(|v| v.trim().to_string()).collect())
+        .collect()
+}
+
+fn cartesian_product(lists: &[Vec<String>]) -> Vec<VariantKey> {
+    lists.iter().fold(vec![vec![]], |keys, list| {
+        keys.iter()
+            .flat_map(|key| {
+                list.iter().map(move |value| {
+                    let mut key = key.clone();
+                    key.push(value.clone());
+                    key
+                })
+            })
+            .collect()
+    })
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>> {
+    match headers.get(name) {
+        Some(value) => Ok(Some(value.to_str().map_err(|_| {
+            Error::InvalidHeaders(format!("Invalid {} header: {:?}", name, value))
+        })?)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the `Variants` header of the given response headers.
+pub(crate) fn variants_of(headers: &HeaderMap) -> Result<Option<Variants>> {
+    header_str(headers, VARIANTS)?
+        .map(Variants::parse)
+        .transpose()
+}
+
+/// Returns the variant keys which the given response headers represent.
+pub(crate) fn variant_keys_of(headers: &HeaderMap) -> Result<Vec<VariantKey>> {
+    Ok(header_str(headers, VARIANT_KEY)?
+        .map(parse_variant_key)
+        .unwrap_or_default())
+}
+
+/// Returns the variant keys of the given exchange. The request headers name
+/// the variant key if the response has no `Variant-Key` header.
+pub(crate) fn exchange_variant_keys(exchange: &Exchange) -> Result<Vec<VariantKey>> {
+    let headers = exchange.response.headers();
+    let mut variant_keys = variant_keys_of(headers)?;
+    if variant_keys.is_empty() {
+        if let Some(variants) = variants_of(headers)? {
+            variant_keys.extend(variants.key_of(exchange.request.headers()));
+        }
+    }
+    Ok(variant_keys)
+}
+
+/// Checks that two exchanges for the same URL are variants of it: they have
+/// the same `Variants` header and disjoint variant keys.
+pub(crate) fn check_variants(exchange: &Exchange, other: &Exchange) -> Result<()> {
+    let variants = exchange.response.headers().get(VARIANTS);
+    let keys = exchange_variant_keys(exchange)?;
+    let other_keys = exchange_variant_keys(other)?;
+    ensure!(
+        variants.is_some()
+            && variants == other.response.headers().get(VARIANTS)
+            && !keys.is_empty()
+            && !other_keys.is_empty()
+            && keys.iter().all(|key| !other_keys.contains(key)),
+        Error::InvalidBundle(format!("Duplicate URL: {}", exchange.request.uri()))
+    );
+    Ok(())
+}
+
+/// Selects the best exchange for the given request among exchanges for the
+/// same URL, following the cache behaviour of HTTP Representation Variants.
+pub(crate) fn select<'a>(
+    candidates: &[&'a Exchange],
+    request_headers: &HeaderMap,
+) -> Option<&'a Exchange> {
+    let variants = match candidates
+        .iter()
+        .find_map(|exchange| variants_of(exchange.response.headers()).ok().flatten())
+    {
+        Some(variants) => variants,
+        None => return candidates.first().copied(),
+    };
+    variants
+        .preferred_keys(request_headers)
+        .iter()
+        .find_map(|key| {
+            candidates.iter().copied().find(|exchange| {
+                exchange_variant_keys(exchange)
+                    .map(|keys| keys.contains(key))
+                    .unwrap_or(false)
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::Request;
+    use http::header::HeaderValue;
+
+    fn exchange(variants: &str, variant_key: &str) -> Result<Exchange> {
+        crate::test_util::variant("https://example.com/", variants, variant_key)
+    }
+
+    fn request(headers: &[(&'static str, &'static str)]) -> Result<Request> {
+        let mut request = Request::get("https://example.com/").body(())?;
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .insert(*name, HeaderValue::from_static(value));
+        }
+        Ok(request)
+    }
+
+    #[test]
+    fn parse_variants() -> Result<()> {
+        let variants = Variants::parse("Accept-Language;en;fr, Accept-Encoding;gzip;br")?;
+        assert_eq!(
+            variants.keys(),
+            vec![
+                vec!["en", "gzip"],
+                vec!["en", "br"],
+                vec!["fr", "gzip"],
+                vec!["fr", "br"],
+            ]
+        );
+        for (i, key) in variants.keys().iter().enumerate() {
+            assert_eq!(&variants.key_at(i as u64), key);
+            let headers = variants.request_headers(key)?;
+            assert_eq!(variants.key_of(&headers).as_ref(), Some(key));
+        }
+        assert_eq!(variants.key_of(&HeaderMap::new()), None);
+        assert!(Variants::parse("Accept-Language").is_err());
+        assert!(Variants::parse(";en").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_variant_keys() {
+        assert_eq!(
+            parse_variant_key("en;gzip, fr;br"),
+            vec![vec!["en", "gzip"], vec!["fr", "br"]]
+        );
+    }
+
+    #[test]
+    fn select_language() -> Result<()> {
+        let variants = "Accept-Language;en;fr;ja";
+        let exchanges = [
+            exchange(variants, "en")?,
+            exchange(variants, "fr")?,
+            exchange(variants, "ja")?,
+        ];
+        let body = |request: Request| {
+            select(&exchanges.iter().collect::<Vec<_>>(), request.headers())
+                .map(|exchange| exchange.response.body().to_vec())
+        };
+        assert_eq!(
+            body(request(&[("accept-language", "fr, en;q=0.8")])?),
+            Some(b"fr".to_vec())
+        );
+        assert_eq!(
+            body(request(&[("accept-language", "ja-JP;q=0.5, fr;q=0.1")])?),
+            Some(b"ja".to_vec())
+        );
+        // Fallbacks to the first available value.
+        assert_eq!(
+            body(request(&[("accept-language", "de")])?),
+            Some(b"en".to_vec())
+        );
+        assert_eq!(body(request(&[])?), Some(b"en".to_vec()));
+        Ok(())
+    }
+
+    #[test]
+    fn select_multiple_axes() -> Result<()> {
+        let variants = "Accept;text/html;application/json, Accept-Encoding;gzip;br";
+        let exchanges = [
+            exchange(variants, "text/html;gzip, text/html;br")?,
+            exchange(variants, "application/json;gzip")?,
+            exchange(variants, "application/json;br")?,
+        ];
+        let body = |request: Request| {
+            select(&exchanges.iter().collect::<Vec<_>>(), request.headers())
+                .map(|exchange| exchange.response.body().to_vec())
+        };
+        assert_eq!(
+            body(request(&[
+                ("accept", "application/*"),
+                ("accept-encoding", "gzip;q=0.5, br")
+            ])?),
+            Some(b"application/json;br".to_vec())
+        );
+        assert_eq!(
+            body(request(&[
+                ("accept", "text/html"),
+                ("accept-encoding", "br")
+            ])?),
+            Some(b"text/html;gzip, text/html;br".to_vec())
+        );
+        Ok(())
+    }
+}
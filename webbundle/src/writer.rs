@@ -0,0 +1,286 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bundle::{self, Body, Bundle, Exchange, Uri, Version};
+use crate::encoder::{self, ResponseLocation, SharedResponses};
+use crate::options::HeaderPolicy;
+use crate::prelude::*;
+use crate::validation;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read as _, Seek as _, SeekFrom, Write};
+
+/// Writes a bundle without holding every exchange in memory.
+///
+/// Responses are spilled to a temporary file as they are added, because the
+/// index section precedes the responses section. The whole bundle is written
+/// to the underlying writer when [`finish`](BundleWriter::finish) is called.
+///
+/// Signatures are not supported. Use [`Builder`](crate::Builder) to sign a
+/// bundle.
+pub struct BundleWriter<W: Write> {
+    write: W,
+    /// The metadata of the bundle, which has no exchanges.
+    bundle: Bundle,
+    responses: BufWriter<File>,
+    responses_length: usize,
+    responses_count: usize,
+    response_locations: Vec<ResponseLocation>,
+    header_policy: HeaderPolicy,
+    shared_responses: Option<SharedResponses>,
+    /// The exchanges added for each URL key, without their bodies, to check
+    /// the exchanges for the same URL.
+    exchanges_by_url: HashMap<String, Vec<Exchange>>,
+}
+
+impl<W: Write> BundleWriter<W> {
+    /// Creates a writer which writes a bundle of the given version to `write`.
+    pub fn new(write: W, version: Version) -> Result<BundleWriter<W>> {
+        Ok(BundleWriter {
+            write,
+            bundle: Bundle {
+                version,
+                primary_url: None,
+                manifest: None,
+                signatures: None,
+                critical: Vec::new(),
+                custom_sections: Vec::new(),
+                section_order: Vec::new(),
+                integrity_block: None,
+                exchanges: Vec::new(),
+                url_index: Default::default(),
+                shares_responses: false,
+            },
+            responses: BufWriter::new(tempfile::tempfile()?),
+            responses_length: 0,
+            responses_count: 0,
+            response_locations: Vec::new(),
+            header_policy: HeaderPolicy::default(),
+            shared_responses: None,
+            exchanges_by_url: HashMap::new(),
+        })
+    }
+
+    /// Sets the primary url.
+    pub fn primary_url(mut self, primary_url: Uri) -> Self {
+        self.bundle.primary_url = Some(primary_url);
+        self
+    }
+
+    /// Sets the manifest url.
+    pub fn manifest(mut self, manifest: Uri) -> Self {
+        self.bundle.manifest = Some(manifest);
+        self
+    }
+
+    /// Sets the policy for response headers which a bundle must not have.
+    /// [`add_exchange`](BundleWriter::add_exchange) fails on them by default.
+    pub fn header_policy(mut self, header_policy: HeaderPolicy) -> Self {
+        self.header_policy = header_policy;
+        self
+    }
+
+    /// Writes identical responses only once, as
+    /// [`EncoderOptions::dedup_responses`](crate::EncoderOptions::dedup_responses)
+    /// does. The default is false.
+    pub fn dedup_responses(mut self, dedup_responses: bool) -> Self {
+        self.shared_responses = dedup_responses.then(SharedResponses::default);
+        self
+    }
+
+    /// Adds an exchange. The response is written to a temporary file
+    /// immediately, and the exchange can be dropped afterwards.
+    ///
+    /// Returns an error if the exchange can't be in the bundle, for example,
+    /// if its URL is already added and it is not a variant of the URL.
+    pub fn add_exchange(&mut self, mut exchange: Exchange) -> Result<()> {
+        if self.bundle.version == Version::VersionB1 {
+            ensure!(
+                self.bundle.primary_url.is_some(),
+                Error::InvalidBundle("primary_url is required in version b1".to_string())
+            );
+        }
+        crate::builder::check_request(&self.bundle.version, &exchange)?;
+        validation::apply_header_policy(self.header_policy, &mut exchange)?;
+        let key = bundle::url_key(exchange.request.uri());
+        bundle::check_same_url(
+            &self.bundle.version,
+            &exchange,
+            self.exchanges_by_url.get(&key).into_iter().flatten(),
+        )?;
+        let location = if let Some(shared_responses) = &mut self.shared_responses {
+            // The response is buffered to be compared with the ones written.
+            let mut response = Vec::new();
+            let mut location =
+                encoder::encode_response(&mut response, &exchange, self.responses_length)?;
+            let responses = &mut self.responses;
+            let is_shared = shared_responses.share(&response, &mut location, |offset| {
+                // Read back the response written to the temporary file.
+                responses.flush()?;
+                let file = responses.get_mut();
+                let mut written = vec![0; response.len()];
+                file.seek(SeekFrom::Start(offset as u64))?;
+                let read = file.read_exact(&mut written);
+                file.seek(SeekFrom::End(0))?;
+                Ok(read.is_ok() && written == response)
+            })?;
+            if !is_shared {
+                self.responses.write_all(&response)?;
+                self.responses_length += location.length;
+                self.responses_count += 1;
+            }
+            location
+        } else {
+            let location =
+                encoder::encode_response(&mut self.responses, &exchange, self.responses_length)?;
+            self.responses_length += location.length;
+            self.responses_count += 1;
+            location
+        };
+        self.response_locations.push(location);
+        let mut metadata = exchange;
+        *metadata.response.body_mut() = Body::new();
+        self.exchanges_by_url.entry(key).or_default().push(metadata);
+        Ok(())
+    }
+
+    /// Writes the bundle to the underlying writer, returning the writer.
+    pub fn finish(self) -> Result<W> {
+        let mut responses = self.responses.into_inner().map_err(|e| e.into_error())?;
+        responses.seek(SeekFrom::Start(0))?;
+        let mut write = encoder::encode_with_encoded_responses(
+            self.write,
+            &self.bundle,
+            self.response_locations,
+            self.responses_count,
+            responses,
+            self.responses_length,
+        )?;
+        write.flush()?;
+        Ok(write)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{exchange, variant};
+
+    fn exchanges() -> Result<Vec<Exchange>> {
+        Ok(vec![
+            exchange("https://example.com/b", "b")?,
+            exchange("https://example.com/a", "a")?,
+            variant("https://example.com/c", "Accept-Language;en;fr", "en")?,
+            variant("https://example.com/c", "Accept-Language;en;fr", "fr")?,
+        ])
+    }
+
+    fn write(version: Version, exchanges: Vec<Exchange>) -> Result<Vec<u8>> {
+        let mut writer =
+            BundleWriter::new(Vec::new(), version)?.primary_url("https://example.com/a".parse()?);
+        for exchange in exchanges {
+            writer.add_exchange(exchange)?;
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn same_as_encode() -> Result<()> {
+        let bundle = exchanges()?
+            .into_iter()
+            .fold(Bundle::builder(), |builder, exchange| {
+                builder.exchange(exchange)
+            })
+            .version(Version::VersionB1)
+            .primary_url("https://example.com/a".parse()?)
+            .manifest("https://example.com/manifest.json".parse()?)
+            .build()?;
+        let mut writer = BundleWriter::new(Vec::new(), Version::VersionB1)?
+            .primary_url("https://example.com/a".parse()?)
+            .manifest("https://example.com/manifest.json".parse()?);
+        for exchange in exchanges()? {
+            writer.add_exchange(exchange)?;
+        }
+        let bytes = writer.finish()?;
+        assert_eq!(bytes, bundle.encode()?);
+
+        let decoded = Bundle::from_bytes(bytes)?;
+        assert_eq!(decoded.exchanges().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn version1() -> Result<()> {
+        let mut exchanges = exchanges()?;
+        exchanges.truncate(2);
+        let bytes = write(Version::Version1, exchanges)?;
+        let bundle = Bundle::from_bytes(bytes)?;
+        assert_eq!(
+            bundle.primary_url(),
+            &Some("https://example.com/a".parse()?)
+        );
+        // Exchanges are in the order of the responses section, which is the
+        // order in which they are added.
+        let exchange = &bundle.exchanges()[0];
+        assert_eq!(exchange.request.uri(), "https://example.com/b");
+        assert_eq!(exchange.response.body(), &b"b"[..]);
+
+        // Variants are not supported in version 1.
+        assert!(write(Version::Version1, self::exchanges()?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn add_invalid_exchange() -> Result<()> {
+        // Version b1 requires the primary url.
+        let mut writer = BundleWriter::new(Vec::new(), Version::VersionB1)?;
+        assert!(writer
+            .add_exchange(exchange("https://example.com/a", "a")?)
+            .is_err());
+
+        let mut writer = BundleWriter::new(Vec::new(), Version::VersionB1)?
+            .primary_url("https://example.com/a".parse()?);
+        for exchange in exchanges()? {
+            writer.add_exchange(exchange)?;
+        }
+        // Errors are returned as soon as the exchange is added.
+        assert!(writer
+            .add_exchange(exchange("https://example.com/a", "a")?)
+            .is_err());
+        assert!(writer
+            .add_exchange(variant(
+                "https://example.com/c",
+                "Accept-Language;en;fr",
+                "fr"
+            )?)
+            .is_err());
+        assert!(writer
+            .add_exchange(variant(
+                "https://example.com/c",
+                "Accept-Language;de;fr",
+                "de"
+            )?)
+            .is_err());
+        let bundle = Bundle::from_bytes(writer.finish()?)?;
+        assert_eq!(bundle.exchanges().len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> Result<()> {
+        let bundle = Bundle::from_bytes(write(Version::VersionB2, Vec::new())?)?;
+        assert!(bundle.exchanges().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Golden file tests against the bundles in `tests/golden`, which are
+//! generated by `tests/golden/generate.py`. The bundles are self-generated,
+//! not produced by other tools.
+
+use serde_json::{json, Value};
+use std::path::Path;
+use webbundle::{Bundle, DecoderOptions, ValidationReport};
+
+fn dump(bundle: &Bundle, report: &ValidationReport) -> Value {
+    let uri = |uri: &Option<webbundle::Uri>| uri.as_ref().map(ToString::to_string);
+    json!({
+        "version": format!("{:?}", bundle.version()),
+        "primaryUrl": uri(bundle.primary_url()),
+        "manifest": uri(bundle.manifest()),
+        "critical": bundle.critical_sections(),
+        "customSections": bundle
+            .custom_sections()
+            .iter()
+            .map(|(name, bytes)| json!([name, data_encoding::HEXLOWER.encode(bytes)]))
+            .collect::<Vec<_>>(),
+        "exchanges": bundle
+            .exchanges()
+            .iter()
+            .map(|exchange| {
+                let headers = exchange
+                    .response
+                    .headers()
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), json!(value.to_str().unwrap())))
+                    .collect::<serde_json::Map<_, _>>();
+                json!({
+                    "url": exchange.request.uri().to_string(),
+                    "status": exchange.response.status().as_u16(),
+                    "headers": headers,
+                    "body": String::from_utf8_lossy(exchange.response.body()),
+                })
+            })
+            .collect::<Vec<_>>(),
+        "issues": report
+            .issues()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[test]
+fn golden() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut paths = std::fs::read_dir(&dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "wbn"));
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let bytes = std::fs::read(&path)?;
+        let expected: Value = serde_json::from_slice(&std::fs::read(path.with_extension("json"))?)?;
+
+        let (bundle, report) =
+            Bundle::from_bytes_with_options(bytes.clone(), &DecoderOptions::default())?;
+        assert_eq!(dump(&bundle, &report), expected, "{}", path.display());
+
+        // A canonical bundle is encoded into the same bytes.
+        let encoded = bundle.encode()?;
+        if report.is_valid() {
+            assert!(
+                encoded == bytes,
+                "{} is not encoded into the same bytes",
+                path.display()
+            );
+        }
+        let (bundle, report) =
+            Bundle::from_bytes_with_options(encoded, &DecoderOptions::default())?;
+        assert!(report.is_valid(), "{}: {:?}", path.display(), report);
+        let mut expected = expected;
+        expected["issues"] = json!([]);
+        assert_eq!(dump(&bundle, &report), expected, "{}", path.display());
+    }
+    Ok(())
+}
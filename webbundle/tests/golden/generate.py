@@ -0,0 +1,199 @@
+#!/usr/bin/env python3
+# Copyright 2020 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+"""Generates the golden files, `*.wbn` and their expected `*.json`.
+
+The bundles are encoded by this script rather than the webbundle crate, but
+follow the same reading of the specification, so they are not a conformance
+test against other tools. Sections are in the order given here, and responses
+are in the order of the input, not of the index.
+
+Usage: python3 generate.py
+"""
+
+import json
+import os
+import struct
+
+MAGIC = "🌐📦".encode()
+
+
+class Raw(bytes):
+    """An already encoded CBOR item."""
+
+
+def head(major_type, argument, size=None):
+    if size is None:
+        if argument < 24:
+            return bytes([major_type << 5 | argument])
+        size = next(size for size in (1, 2, 4, 8) if argument < 1 << (8 * size))
+    info = {1: 24, 2: 25, 4: 26, 8: 27}[size]
+    return bytes([major_type << 5 | info]) + argument.to_bytes(size, "big")
+
+
+def cbor(value):
+    """Encodes the given value canonically."""
+    if isinstance(value, Raw):
+        return bytes(value)
+    if isinstance(value, int):
+        return head(0, value)
+    if isinstance(value, bytes):
+        return head(2, len(value)) + value
+    if isinstance(value, str):
+        return head(3, len(value.encode())) + value.encode()
+    if isinstance(value, list):
+        return head(4, len(value)) + b"".join(cbor(item) for item in value)
+    if isinstance(value, dict):
+        entries = sorted((cbor(key), cbor(item)) for key, item in value.items())
+        return head(5, len(entries)) + b"".join(key + item for key, item in entries)
+    raise TypeError(value)
+
+
+def response(status, headers, body):
+    headers = {b":status": str(status).encode(), **headers}
+    return cbor([cbor(headers), body])
+
+
+def bundle(version, sections, exchanges, primary_url=None, b1=False, long_lengths=()):
+    """Encodes a bundle. `sections` is a list of (name, value) in the order of
+    the section table, where the value of "index" is ignored and "responses"
+    is appended. The lengths of `long_lengths` are encoded in two bytes, which
+    is not canonical."""
+    responses = [response(*exchange[1:]) for exchange in exchanges]
+    responses_section = head(4, len(responses))
+    index = {}
+    for (url, *_), encoded in zip(exchanges, responses):
+        location = [len(responses_section), len(encoded)]
+        index[url] = [b""] + location if b1 else location
+        responses_section += encoded
+    encoded_sections = [
+        (name, cbor(index) if name == "index" else cbor(value))
+        for name, value in sections
+    ] + [("responses", responses_section)]
+    section_lengths = []
+    for name, encoded in encoded_sections:
+        length = len(encoded)
+        if name in long_lengths:
+            length = Raw(head(0, length, size=2))
+        section_lengths += [name, length]
+    items = [MAGIC, version] + ([primary_url] if b1 else [])
+    items += [
+        cbor(section_lengths),
+        Raw(head(4, len(encoded_sections))
+            + b"".join(encoded for _, encoded in encoded_sections)),
+    ]
+    encoded = head(4, len(items) + 1) + b"".join(cbor(item) for item in items)
+    return encoded + cbor(struct.pack(">Q", len(encoded) + 9))
+
+
+def expected(version, exchanges, primary_url=None, manifest=None, critical=(),
+             custom_sections=(), issues=()):
+    return {
+        "version": version,
+        "primaryUrl": primary_url,
+        "manifest": manifest,
+        "critical": list(critical),
+        "customSections": [[name, cbor(value).hex()] for name, value in custom_sections],
+        "exchanges": [
+            {
+                "url": url,
+                "status": status,
+                "headers": {name.decode(): value.decode() for name, value in headers.items()},
+                "body": body.decode(),
+            }
+            for url, status, headers, body in exchanges
+        ],
+        "issues": list(issues),
+    }
+
+
+def main():
+    html = {b"content-type": b"text/html"}
+    css = {b"content-type": b"text/css"}
+    exchanges = [
+        ("https://example.com/index.html", 200, html, b"<link rel=stylesheet href=a.css>"),
+        ("https://example.com/a.css", 200, css, b"body { color: red; }"),
+    ]
+    fixtures = {}
+
+    primary_url = "https://example.com/index.html"
+    fixtures["version1"] = (
+        bundle(b"1\0\0\0", [("index", None), ("primary", primary_url)], exchanges),
+        expected("Version1", exchanges, primary_url=primary_url),
+    )
+
+    custom = {"key": 1}
+    b2_exchanges = exchanges + [("https://example.com/missing", 404, {}, b"")]
+    fixtures["b2-critical-custom"] = (
+        bundle(
+            b"b2\0\0",
+            [
+                ("index", None),
+                ("critical", ["index"]),
+                ("primary", primary_url),
+                ("x-custom", custom),
+            ],
+            b2_exchanges,
+        ),
+        expected(
+            "VersionB2",
+            b2_exchanges,
+            primary_url=primary_url,
+            critical=["index"],
+            custom_sections=[("x-custom", custom)],
+        ),
+    )
+
+    manifest = "https://example.com/manifest.json"
+    fixtures["b1-manifest"] = (
+        bundle(
+            b"b1\0\0",
+            [("index", None), ("manifest", manifest)],
+            exchanges,
+            primary_url=primary_url,
+            b1=True,
+        ),
+        expected("VersionB1", exchanges, primary_url=primary_url, manifest=manifest),
+    )
+
+    # The length of the primary section is not in the shortest form.
+    encoded = bundle(
+        b"1\0\0\0",
+        [("primary", primary_url), ("index", None)],
+        exchanges,
+        long_lengths=["primary"],
+    )
+    offset = encoded.index(cbor("primary")) + len(cbor("primary"))
+    fixtures["version1-non-canonical"] = (
+        encoded,
+        expected(
+            "Version1",
+            exchanges,
+            primary_url=primary_url,
+            issues=["Non-canonical CBOR at offset {}".format(offset)],
+        ),
+    )
+
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, (encoded, dump) in fixtures.items():
+        with open(os.path.join(directory, name + ".wbn"), "wb") as f:
+            f.write(encoded)
+        with open(os.path.join(directory, name + ".json"), "w") as f:
+            json.dump(dump, f, indent=2)
+            f.write("\n")
+
+
+if __name__ == "__main__":
+    main()